@@ -0,0 +1,358 @@
+// Benchmarks. These aren't part of the tour; run them with
+// `cargo run --release -- bench-timers` or `cargo run --release -- bench-models`.
+
+use futures::stream::{FuturesUnordered, StreamExt};
+
+use std::cmp::{self, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::fs;
+use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::executor::block_on;
+use crate::rng::Rng;
+use crate::trace;
+use crate::wheel::{self, Wheel};
+use crate::work;
+
+// Run each model of computation with more and more tasks, and compare how long
+// it takes and how much it costs. The work is shortened to 10ms so that the
+// sequential models finish in reasonable time, and we skip running models with
+// more tasks than they can reasonably handle.
+//
+// The text claims that at large scale threads are too costly. Compare the
+// peak thread count and memory of `multi_threaded` and `async_concurrent` as
+// the number of tasks grows.
+pub fn models() {
+    let duration = Duration::from_millis(10);
+
+    println!(
+        "{:>16} {:>8} {:>12} {:>8} {:>12} {:>10} {:>10}",
+        "model", "tasks", "time", "threads", "peak rss", "vol cs", "invol cs"
+    );
+    // Printing messages for 100,000 tasks would take longer than the work.
+    trace::with_recorder(Arc::new(trace::Discard), || {
+        for &n in &[1, 10, 100, 1_000, 10_000, 100_000] {
+            for &model in &[
+                Model::Sequential,
+                Model::MultiThreaded,
+                Model::AsyncSeq,
+                Model::AsyncConcurrent,
+            ] {
+                // Say that we skipped a run, rather than leave a gap in the
+                // table which looks like a mistake.
+                if n > model.max_tasks() {
+                    println!(
+                        "{:>16} {:>8} skipped (limit {})",
+                        model.name(),
+                        n,
+                        model.max_tasks()
+                    );
+                    continue;
+                }
+
+                match measure(|| model.run(n, duration)) {
+                    Ok(m) => println!(
+                        "{:>16} {:>8} {:>12?} {:>8} {:>9} KiB {:>10} {:>10}",
+                        model.name(),
+                        n,
+                        m.time,
+                        m.peak_threads,
+                        m.peak_rss_kib,
+                        m.voluntary_switches,
+                        m.involuntary_switches
+                    ),
+                    Err(e) => println!("{:>16} {:>8} failed: {}", model.name(), n, e),
+                }
+            }
+        }
+    });
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Model {
+    Sequential,
+    MultiThreaded,
+    AsyncSeq,
+    AsyncConcurrent,
+}
+
+impl Model {
+    fn name(self) -> &'static str {
+        match self {
+            Model::Sequential => "sequential",
+            Model::MultiThreaded => "multi_threaded",
+            Model::AsyncSeq => "async_seq",
+            Model::AsyncConcurrent => "async_concurrent",
+        }
+    }
+
+    // The most tasks it is sensible to run. The sequential models take 10ms per
+    // task, and too many threads can bring a computer to a halt.
+    fn max_tasks(self) -> usize {
+        match self {
+            Model::Sequential | Model::AsyncSeq => 100,
+            Model::MultiThreaded => 10_000,
+            Model::AsyncConcurrent => 100_000,
+        }
+    }
+
+    // These are the same as the models in `main.rs`, but for any number of
+    // tasks.
+    fn run(self, n: usize, duration: Duration) -> io::Result<()> {
+        match self {
+            Model::Sequential => {
+                for x in 0..n {
+                    work::do_work(x as i32, duration);
+                }
+            }
+            Model::MultiThreaded => {
+                let mut threads = Vec::with_capacity(n);
+                for x in 0..n {
+                    // We might not be allowed this many threads. If so, we wait
+                    // for the threads we did start before reporting the error.
+                    let spawned = thread::Builder::new()
+                        .spawn(move || work::do_work(x as i32, duration));
+                    match spawned {
+                        Ok(t) => threads.push(t),
+                        Err(e) => {
+                            for t in threads {
+                                t.join().unwrap();
+                            }
+                            return Err(e);
+                        }
+                    }
+                }
+                for t in threads {
+                    t.join().unwrap();
+                }
+            }
+            Model::AsyncSeq => block_on(async move {
+                for x in 0..n {
+                    await!(work::do_work_async(x as i32, duration));
+                }
+            }),
+            Model::AsyncConcurrent => block_on(async move {
+                // `join!` only works for a fixed number of futures.
+                // `FuturesUnordered` polls any number of futures concurrently,
+                // and yields their results as they finish.
+                let mut tasks = FuturesUnordered::new();
+                for x in 0..n {
+                    tasks.push(work::do_work_async(x as i32, duration));
+                }
+                while let Some(()) = await!(tasks.next()) {}
+            }),
+        }
+        Ok(())
+    }
+}
+
+struct Measurements {
+    time: Duration,
+    peak_threads: usize,
+    peak_rss_kib: usize,
+    voluntary_switches: i64,
+    involuntary_switches: i64,
+}
+
+// Run `f`, measuring the time it takes, and the peak number of threads, peak
+// memory, and context switches while it runs. The numbers come from Linux, on
+// other systems they'll be zero.
+fn measure(f: impl FnOnce() -> io::Result<()>) -> io::Result<Measurements> {
+    // Linux only keeps track of the peak memory use for the whole life of the
+    // process, but we can ask it to start again from now.
+    let _ = fs::write("/proc/self/clear_refs", "5");
+
+    // There's no record of the peak number of threads, so we use a thread to
+    // keep checking. It is counted too, as is the timer thread.
+    let done = Arc::new(AtomicBool::new(false));
+    let sampler = {
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut peak = 0;
+            while !done.load(Ordering::SeqCst) {
+                peak = cmp::max(peak, proc_status("Threads:"));
+                thread::sleep(Duration::from_millis(1));
+            }
+            peak
+        })
+    };
+
+    let switches_before = context_switches();
+    let start = Instant::now();
+    let result = f();
+    let time = start.elapsed();
+    let switches_after = context_switches();
+
+    done.store(true, Ordering::SeqCst);
+    let peak_threads = sampler.join().unwrap();
+    result?;
+
+    Ok(Measurements {
+        time,
+        peak_threads,
+        peak_rss_kib: proc_status("VmHWM:"),
+        voluntary_switches: switches_after.0 - switches_before.0,
+        involuntary_switches: switches_after.1 - switches_before.1,
+    })
+}
+
+// Read a number from `/proc/self/status`, e.g., `proc_status("Threads:")`.
+fn proc_status(field: &str) -> usize {
+    let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+    status
+        .lines()
+        .find(|line| line.starts_with(field))
+        .and_then(|line| line[field.len()..].split_whitespace().next())
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0)
+}
+
+// The number of voluntary (e.g., waiting for a lock or a sleep) and involuntary
+// (the OS decided to run something else) context switches so far, for all the
+// threads in this process.
+fn context_switches() -> (i64, i64) {
+    unsafe {
+        let mut usage: libc::rusage = mem::zeroed();
+        if libc::getrusage(libc::RUSAGE_SELF, &mut usage) != 0 {
+            return (0, 0);
+        }
+        (usage.ru_nvcsw as i64, usage.ru_nivcsw as i64)
+    }
+}
+
+// Compare the timing wheel used by our timer with a timer built on a binary
+// heap, which is the usual first attempt at a timer (and roughly what we had
+// when we kept the deadlines in a `BTreeMap`).
+//
+// For each size, we add `n` timeouts spread over ten seconds, cancel half of
+// them (as happens when work finishes early or is dropped), then step through
+// time a millisecond at a time until they've all expired.
+pub fn timers() {
+    println!(
+        "{:>8} {:>10} {:>12} {:>12} {:>12}",
+        "timers", "timer", "insert", "cancel", "expire"
+    );
+    for &n in &[1_000, 10_000, 100_000, 1_000_000] {
+        print_row(n, "wheel", bench_timer(n, Wheel::new()));
+        print_row(n, "heap", bench_timer(n, HeapTimer::new()));
+    }
+}
+
+fn print_row(n: usize, name: &str, (insert, cancel, expire): (Duration, Duration, Duration)) {
+    println!(
+        "{:>8} {:>10} {:>12?} {:>12?} {:>12?}",
+        n, name, insert, cancel, expire
+    );
+}
+
+// The operations our timer thread needs.
+trait BenchTimer {
+    type Key: Copy;
+
+    fn insert(&mut self, when: u64, value: usize) -> Self::Key;
+    fn cancel(&mut self, key: Self::Key) -> Option<usize>;
+    fn advance(&mut self, target: u64) -> Vec<usize>;
+}
+
+impl BenchTimer for Wheel<usize> {
+    type Key = wheel::Key;
+
+    fn insert(&mut self, when: u64, value: usize) -> wheel::Key {
+        Wheel::insert(self, when, value)
+    }
+
+    fn cancel(&mut self, key: wheel::Key) -> Option<usize> {
+        Wheel::cancel(self, key)
+    }
+
+    fn advance(&mut self, target: u64) -> Vec<usize> {
+        Wheel::advance(self, target)
+    }
+}
+
+// A timer which keeps deadlines in a binary heap. Inserting costs O(log n). A
+// heap can't remove an arbitrary element cheaply, so cancelling just forgets
+// the value and the stale deadline is skipped when it reaches the top.
+struct HeapTimer {
+    heap: BinaryHeap<Reverse<(u64, u64)>>,
+    values: HashMap<u64, usize>,
+    next_key: u64,
+}
+
+impl HeapTimer {
+    fn new() -> HeapTimer {
+        HeapTimer {
+            heap: BinaryHeap::new(),
+            values: HashMap::new(),
+            next_key: 0,
+        }
+    }
+}
+
+impl BenchTimer for HeapTimer {
+    type Key = u64;
+
+    fn insert(&mut self, when: u64, value: usize) -> u64 {
+        let key = self.next_key;
+        self.next_key += 1;
+        self.heap.push(Reverse((when, key)));
+        self.values.insert(key, value);
+        key
+    }
+
+    fn cancel(&mut self, key: u64) -> Option<usize> {
+        self.values.remove(&key)
+    }
+
+    fn advance(&mut self, target: u64) -> Vec<usize> {
+        let mut expired = Vec::new();
+        while let Some(&Reverse((when, key))) = self.heap.peek() {
+            if when > target {
+                break;
+            }
+            self.heap.pop();
+            if let Some(value) = self.values.remove(&key) {
+                expired.push(value);
+            }
+        }
+        expired
+    }
+}
+
+// Returns the time taken to insert, cancel, and expire timeouts.
+fn bench_timer<T: BenchTimer>(n: usize, mut timer: T) -> (Duration, Duration, Duration) {
+    const SPAN: u64 = 10_000;
+
+    // Use a fixed seed, so every run (and both timers) sees the same deadlines.
+    let mut rng = Rng::new(0);
+    let deadlines: Vec<u64> = (0..n).map(|_| 1 + rng.below(SPAN)).collect();
+
+    let start = Instant::now();
+    let keys: Vec<T::Key> = deadlines
+        .iter()
+        .enumerate()
+        .map(|(i, &when)| timer.insert(when, i))
+        .collect();
+    let insert = start.elapsed();
+
+    let start = Instant::now();
+    for key in keys.iter().step_by(2) {
+        timer.cancel(*key);
+    }
+    let cancel = start.elapsed();
+
+    let start = Instant::now();
+    let mut expired = 0;
+    for tick in 1..=SPAN {
+        expired += timer.advance(tick).len();
+    }
+    let expire = start.elapsed();
+    assert_eq!(expired, n / 2);
+
+    (insert, cancel, expire)
+}
@@ -0,0 +1,320 @@
+// Channels for sending values between tasks.
+//
+// A channel is a queue with two ends. Any number of `Sender`s can send values
+// into it (it is multi-producer), and a single `Receiver` takes them out in the
+// order they were sent (single-consumer), so it's an 'MPSC' channel. The
+// senders and the receiver can be in different tasks, or on different threads.
+//
+// If the receiver finds the queue empty, `recv` waits: it leaves its waker with
+// the channel, and the next `send` wakes it. Once every sender has been
+// dropped, nothing more can arrive, and `recv` returns `None`.
+//
+// An unbounded channel never makes a sender wait, so if the receiver is slower
+// than the senders, the queue grows and grows. A bounded channel holds at most
+// `capacity` values; when it is full, `send` waits until the receiver takes a
+// value out. That is 'backpressure': a slow receiver slows down the senders,
+// rather than leaving a pile of work in between.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{LocalWaker, Poll, Waker};
+
+// A channel which holds at most `capacity` values.
+pub fn bounded<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "a bounded channel needs room for at least one value");
+    channel(Some(capacity))
+}
+
+// A channel which can hold any number of values.
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    channel(None)
+}
+
+fn channel<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        queue: VecDeque::new(),
+        capacity,
+        senders: 1,
+        receiver_alive: true,
+        recv_waker: None,
+        send_wakers: Vec::new(),
+    }));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+struct Shared<T> {
+    queue: VecDeque<T>,
+    // `None` if the channel is unbounded.
+    capacity: Option<usize>,
+    // The number of `Sender`s which haven't been dropped.
+    senders: usize,
+    receiver_alive: bool,
+    // Wakes the receiver, if it is waiting for a value.
+    recv_waker: Option<Waker>,
+    // Wakes the senders waiting for room in the queue, one waker per task.
+    send_wakers: Vec<Waker>,
+}
+
+impl<T> Shared<T> {
+    fn wake_receiver(&mut self) {
+        if let Some(waker) = self.recv_waker.take() {
+            waker.wake();
+        }
+    }
+
+    // We wake every waiting sender, not just one. A sender which is woken might
+    // have been dropped since it started waiting, and if it was the only one we
+    // woke, the others would wait forever. Those which don't find room wait
+    // again.
+    fn wake_senders(&mut self) {
+        for waker in self.send_wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+// The error when sending to a channel whose receiver has been dropped. We get
+// the value back.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the receiver was dropped")
+    }
+}
+
+impl<T: fmt::Debug> Error for SendError<T> {}
+
+pub struct Sender<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Sender<T> {
+    // Send `value`, waiting for room if the channel is bounded and full.
+    pub fn send(&self, value: T) -> Sending<T> {
+        Sending {
+            sender: self,
+            value: Some(value),
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.lock().unwrap().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.senders -= 1;
+        // If that was the last sender, the receiver might be waiting for a value
+        // which will never come.
+        if shared.senders == 0 {
+            shared.wake_receiver();
+        }
+    }
+}
+
+// A future which completes once a value has been put in the channel.
+pub struct Sending<'a, T> {
+    sender: &'a Sender<T>,
+    // `None` once the value has been sent.
+    value: Option<T>,
+}
+
+impl<'a, T> Future for Sending<'a, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<Self::Output> {
+        // Safe because we never pin `value`, we only move it into the queue.
+        let this = unsafe { Pin::get_unchecked_mut(self) };
+        let mut shared = this.sender.shared.lock().unwrap();
+        let value = this.value.take().expect("polled after sending");
+
+        if !shared.receiver_alive {
+            return Poll::Ready(Err(SendError(value)));
+        }
+        let full = match shared.capacity {
+            Some(capacity) => shared.queue.len() >= capacity,
+            None => false,
+        };
+        if full {
+            this.value = Some(value);
+            // We might be polled many times before there is room, and several
+            // sends in one task (e.g., in a `join!`) share its waker. Waking
+            // the task once wakes all of them, so we only keep one copy.
+            let waker = lw.clone().into_waker();
+            if !shared.send_wakers.iter().any(|w| w.will_wake(&waker)) {
+                shared.send_wakers.push(waker);
+            }
+            return Poll::Pending;
+        }
+
+        shared.queue.push_back(value);
+        shared.wake_receiver();
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Receiver<T> {
+    // Wait for the next value. Returns `None` once every sender has been
+    // dropped and there are no values left.
+    pub fn recv(&mut self) -> Recv<T> {
+        Recv { receiver: self }
+    }
+
+    // The number of values waiting in the channel.
+    pub fn len(&self) -> usize {
+        self.shared.lock().unwrap().queue.len()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.receiver_alive = false;
+        // Nobody will ever make room, so let any waiting senders find out.
+        shared.wake_senders();
+    }
+}
+
+// A future which completes with the next value from the channel.
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<'a, T> Future for Recv<'a, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<Option<T>> {
+        let mut shared = self.receiver.shared.lock().unwrap();
+        if let Some(value) = shared.queue.pop_front() {
+            // There's room for another value now.
+            shared.wake_senders();
+            return Poll::Ready(Some(value));
+        }
+        if shared.senders == 0 {
+            return Poll::Ready(None);
+        }
+        shared.recv_waker = Some(lw.clone().into_waker());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{poll, Flag};
+
+    #[test]
+    fn values_arrive_in_order() {
+        let (tx, mut rx) = unbounded();
+        let (_, lw) = Flag::new();
+        for x in 0..3 {
+            assert_eq!(poll(&mut tx.send(x), &lw), Poll::Ready(Ok(())));
+        }
+        assert_eq!(rx.len(), 3);
+        for x in 0..3 {
+            assert_eq!(poll(&mut rx.recv(), &lw), Poll::Ready(Some(x)));
+        }
+    }
+
+    #[test]
+    fn recv_returns_none_once_the_senders_are_gone() {
+        let (tx, mut rx) = bounded(1);
+        let tx2 = tx.clone();
+        let (flag, lw) = Flag::new();
+        assert_eq!(poll(&mut tx.send(1), &lw), Poll::Ready(Ok(())));
+        drop(tx);
+
+        // The value which was sent still arrives, and there's still a sender.
+        assert_eq!(poll(&mut rx.recv(), &lw), Poll::Ready(Some(1)));
+        let mut recv = rx.recv();
+        assert_eq!(poll(&mut recv, &lw), Poll::Pending);
+
+        // Dropping the last sender wakes the receiver.
+        drop(tx2);
+        assert!(flag.take());
+        assert_eq!(poll(&mut recv, &lw), Poll::Ready(None));
+    }
+
+    #[test]
+    fn send_fails_once_the_receiver_is_gone() {
+        let (tx, rx) = bounded(1);
+        let (flag, lw) = Flag::new();
+        assert_eq!(poll(&mut tx.send(1), &lw), Poll::Ready(Ok(())));
+        let mut send = tx.send(2);
+        assert_eq!(poll(&mut send, &lw), Poll::Pending);
+
+        // Dropping the receiver wakes the waiting sender, which gets its value
+        // back.
+        drop(rx);
+        assert!(flag.take());
+        assert_eq!(poll(&mut send, &lw), Poll::Ready(Err(SendError(2))));
+        assert_eq!(poll(&mut tx.send(3), &lw), Poll::Ready(Err(SendError(3))));
+    }
+
+    #[test]
+    fn bounded_send_waits_for_room() {
+        let (tx, mut rx) = bounded(2);
+        let (send_flag, send_lw) = Flag::new();
+        let (_, recv_lw) = Flag::new();
+        assert_eq!(poll(&mut tx.send(1), &send_lw), Poll::Ready(Ok(())));
+        assert_eq!(poll(&mut tx.send(2), &send_lw), Poll::Ready(Ok(())));
+
+        // The channel is full, so the send waits however often it is polled.
+        let mut send = tx.send(3);
+        assert_eq!(poll(&mut send, &send_lw), Poll::Pending);
+        assert_eq!(poll(&mut send, &send_lw), Poll::Pending);
+        assert_eq!(rx.len(), 2);
+
+        // Taking a value out makes room, and wakes the sender.
+        assert_eq!(poll(&mut rx.recv(), &recv_lw), Poll::Ready(Some(1)));
+        assert!(send_flag.take());
+        assert_eq!(poll(&mut send, &send_lw), Poll::Ready(Ok(())));
+        assert_eq!(poll(&mut rx.recv(), &recv_lw), Poll::Ready(Some(2)));
+        assert_eq!(poll(&mut rx.recv(), &recv_lw), Poll::Ready(Some(3)));
+    }
+
+    // Several sends from one task share its waker, and the channel keeps only
+    // one copy of it, however often they're polled.
+    #[test]
+    fn one_waker_per_task() {
+        let (tx, _rx) = bounded(1);
+        let (_, lw) = Flag::new();
+        let (_, other_lw) = Flag::new();
+        assert_eq!(poll(&mut tx.send(1), &lw), Poll::Ready(Ok(())));
+
+        let mut send2 = tx.send(2);
+        let mut send3 = tx.send(3);
+        for _ in 0..3 {
+            assert_eq!(poll(&mut send2, &lw), Poll::Pending);
+            assert_eq!(poll(&mut send3, &lw), Poll::Pending);
+        }
+        assert_eq!(tx.shared.lock().unwrap().send_wakers.len(), 1);
+
+        // A send from another task leaves its own waker.
+        let mut send4 = tx.send(4);
+        assert_eq!(poll(&mut send4, &other_lw), Poll::Pending);
+        assert_eq!(tx.shared.lock().unwrap().send_wakers.len(), 2);
+    }
+}
@@ -0,0 +1,145 @@
+// The command line.
+//
+// With no arguments, we run the four models from the text in turn. Name one or
+// more models to run those instead (`--list` shows them all), and use the
+// options to change how they run, e.g.,
+//
+//     cargo run -- async_seq async_concurrent --tasks 8 --seed 42
+//
+// We parse the arguments by hand rather than using a library, there are only a
+// few of them.
+
+use std::time::Duration;
+
+use crate::work;
+
+pub const USAGE: &str = "\
+usage: small [MODEL]... [OPTIONS]
+
+Runs each MODEL in turn, or the four models from the text if none are
+given (`sequential`, `multi_threaded`, `async_seq` and `async_concurrent`).
+
+options:
+    --tasks N          the number of pieces of work, for models which can do any
+                       number (default 4, except for `async_spawn`)
+    --duration MS      how long each piece of work takes, in milliseconds
+                       (default 500)
+    --seed N           jitter the durations of the work, and choose failures,
+                       randomly but repeatably using N
+    --executor KIND    how to run async models which don't spawn tasks:
+                       `single` (our single-threaded executor, the default),
+                       `pool` (our thread pool), or `futures` (the futures
+                       library's executor)
+    --format FORMAT    how to show the work: `text` (a message for each event),
+                       `timeline` (messages then a timeline), or `json` (a line
+                       of JSON for each model on stdout, with any messages on
+                       stderr); each model has its own default, and the
+                       benchmarks always print tables
+    --list             list the models
+    --help             print this message";
+
+pub struct Options {
+    // The models to run, in order. Empty means the models from the text.
+    pub models: Vec<String>,
+    // `None` means each model uses its own default.
+    pub tasks: Option<usize>,
+    pub duration: Duration,
+    pub seed: Option<u64>,
+    pub executor: ExecutorKind,
+    // `None` means each model uses its own default.
+    pub format: Option<Format>,
+    pub list: bool,
+    pub help: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecutorKind {
+    // Our single-threaded executor (see `executor.rs`).
+    Single,
+    // Our thread pool (see `pool.rs`), with this many workers.
+    Pool(usize),
+    // The executor from the futures library.
+    Futures,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Timeline,
+    Json,
+}
+
+// Parse `args` (not including the program name). `models` are the names of
+// the models which can be run.
+pub fn parse(mut args: impl Iterator<Item = String>, models: &[&str]) -> Result<Options, String> {
+    let mut options = Options {
+        models: Vec::new(),
+        tasks: None,
+        duration: work::WORK_DURATION,
+        seed: None,
+        executor: ExecutorKind::Single,
+        format: None,
+        list: false,
+        help: false,
+    };
+
+    while let Some(arg) = args.next() {
+        if arg == "--list" {
+            options.list = true;
+            continue;
+        }
+        if arg == "--help" || arg == "-h" {
+            options.help = true;
+            continue;
+        }
+        if !arg.starts_with('-') {
+            if !models.contains(&&*arg) {
+                return Err(format!("unknown model `{}` (try `--list`)", arg));
+            }
+            options.models.push(arg);
+            continue;
+        }
+
+        // Every other option takes a value.
+        let value = args.next().ok_or_else(|| format!("`{}` needs a value", arg))?;
+        match &*arg {
+            "--tasks" => options.tasks = Some(number(&arg, &value)? as usize),
+            "--duration" => options.duration = Duration::from_millis(number(&arg, &value)?),
+            "--seed" => options.seed = Some(number(&arg, &value)?),
+            "--executor" => {
+                options.executor = match &*value {
+                    "single" => ExecutorKind::Single,
+                    "pool" => ExecutorKind::Pool(4),
+                    "futures" => ExecutorKind::Futures,
+                    _ => return Err(format!("unknown executor `{}`", value)),
+                }
+            }
+            "--format" => {
+                options.format = Some(match &*value {
+                    "text" => Format::Text,
+                    "timeline" => Format::Timeline,
+                    "json" => Format::Json,
+                    _ => return Err(format!("unknown format `{}`", value)),
+                })
+            }
+            _ => return Err(format!("unknown option `{}`", arg)),
+        }
+    }
+
+    // The benchmarks print tables, not events, so they have nothing to put in
+    // the JSON.
+    let benchmark = options.models.iter().any(|m| m.starts_with("bench-"));
+    if benchmark && options.format == Some(Format::Json) {
+        return Err("the benchmarks can't use `--format json`".to_owned());
+    }
+    if options.tasks == Some(0) {
+        return Err("`--tasks` must be at least 1".to_owned());
+    }
+    Ok(options)
+}
+
+fn number(name: &str, value: &str) -> Result<u64, String> {
+    value
+        .parse()
+        .map_err(|_| format!("`{}` must be a number, not `{}`", name, value))
+}
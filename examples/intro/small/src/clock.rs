@@ -0,0 +1,70 @@
+// Where the time comes from.
+//
+// Our timer and work functions don't ask the operating system for the time
+// directly, they ask a `Clock`. Usually that is the `SystemClock`, which just
+// passes the question on. But for checking the behaviour of the models, we can
+// use a `VirtualClock`, where time only moves when we say so. Then we can say
+// exactly how many 'ticks' a model takes without waiting around for real time
+// to pass, and without the results depending on how busy the computer is.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    // Block the current thread for `duration`.
+    fn sleep(&self, duration: Duration);
+}
+
+// The real time.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+// A clock which only moves when it is told to.
+pub struct VirtualClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl VirtualClock {
+    pub fn new() -> VirtualClock {
+        VirtualClock {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::from_millis(0)),
+        }
+    }
+
+    // Move the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    // How far the clock has moved since it was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    // Blocking a thread on a virtual clock would wait forever if this thread is
+    // the one which should advance the clock. Instead, sleeping just moves the
+    // clock forward - the thread can't do anything else in the meantime anyway.
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
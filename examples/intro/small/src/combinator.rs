@@ -0,0 +1,199 @@
+// Combining futures.
+//
+// `join!` waits for all of its futures to finish, but only works for a number
+// of futures which is fixed when we write the code. `join_all` does the same
+// for a `Vec` of any number of futures.
+//
+// `race` is the opposite: it waits for the first of its futures to finish and
+// returns that future's result. The other futures - the losers - are dropped as
+// soon as there is a winner. Like any cancelled future, a loser is never polled
+// again, so it stops at whichever `await` it was waiting at and the code after
+// that never runs.
+//
+// This is what `select!` does in the futures library. It is a common source of
+// bugs, because it is easy to forget that the losers might have been half way
+// through something when they were dropped.
+//
+// `try_join` is `join!` for futures which can fail. It waits for all of its
+// futures to succeed, but as soon as one fails, it drops the rest and returns
+// the error - there's no point finishing the other work if the result will be
+// an error anyway.
+//
+// `catch_unwind` catches a panic in a future, and gives it to us as an error,
+// just like `panic::catch_unwind` does for a closure. Normally a panic stops the
+// whole task (see `task.rs`), including any other futures in the same `join!`.
+// Catching the panic around each future means only that one stops.
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{LocalWaker, Poll};
+use std::thread;
+
+// Run all of `futures` concurrently until they have all finished. Returns their
+// results, in order.
+pub fn join_all<F: Future>(futures: Vec<F>) -> JoinAll<F> {
+    JoinAll {
+        outputs: futures.iter().map(|_| None).collect(),
+        futures: futures.into_iter().map(|f| Some(Box::pin(f))).collect(),
+    }
+}
+
+pub struct JoinAll<F: Future> {
+    // Each future is dropped once it has finished.
+    futures: Vec<Option<Pin<Box<F>>>>,
+    outputs: Vec<Option<F::Output>>,
+}
+
+impl<F: Future> Future for JoinAll<F> {
+    type Output = Vec<F::Output>;
+
+    fn poll(self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<Self::Output> {
+        // Safe because the futures are boxed, and we never pin the outputs.
+        let this = unsafe { Pin::get_unchecked_mut(self) };
+        for (future, output) in this.futures.iter_mut().zip(&mut this.outputs) {
+            let ready = match future {
+                Some(f) => f.as_mut().poll(lw),
+                None => continue,
+            };
+            if let Poll::Ready(value) = ready {
+                *future = None;
+                *output = Some(value);
+            }
+        }
+
+        if this.futures.iter().any(Option::is_some) {
+            return Poll::Pending;
+        }
+        Poll::Ready(this.outputs.drain(..).map(Option::unwrap).collect())
+    }
+}
+
+// Run all of `futures` concurrently until one finishes. Returns the index of
+// the future which finished first and its result.
+pub fn race<F: Future>(futures: Vec<F>) -> Race<F> {
+    assert!(!futures.is_empty(), "a race needs at least one future");
+    Race {
+        // Each future is pinned in its own box, so that we can drop the losers
+        // without moving the winner.
+        futures: futures.into_iter().map(Box::pin).collect(),
+    }
+}
+
+pub struct Race<F> {
+    // Empty once the race has been won.
+    futures: Vec<Pin<Box<F>>>,
+}
+
+impl<F: Future> Future for Race<F> {
+    type Output = (usize, F::Output);
+
+    fn poll(mut self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<Self::Output> {
+        // The futures are boxed, so `Race` can be moved and we can treat the
+        // pinned reference as a plain `&mut` (see `Delay` in `work.rs`).
+        let this = &mut *self;
+        let mut winner = None;
+        for (i, future) in this.futures.iter_mut().enumerate() {
+            if let Poll::Ready(output) = future.as_mut().poll(lw) {
+                winner = Some((i, output));
+                break;
+            }
+        }
+
+        match winner {
+            Some(winner) => {
+                // Drop the losers now, rather than whenever the `Race` is
+                // dropped.
+                this.futures.clear();
+                Poll::Ready(winner)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+// Run all of `futures` concurrently. Returns all their results, in order, if
+// they all succeed, or the first error.
+pub fn try_join<T, E, F>(futures: Vec<F>) -> TryJoin<F, T>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    TryJoin {
+        results: futures.iter().map(|_| None).collect(),
+        futures: futures.into_iter().map(|f| Some(Box::pin(f))).collect(),
+    }
+}
+
+pub struct TryJoin<F, T> {
+    // Each future is dropped once it has finished.
+    futures: Vec<Option<Pin<Box<F>>>>,
+    results: Vec<Option<T>>,
+}
+
+impl<T, E, F> Future for TryJoin<F, T>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    type Output = Result<Vec<T>, E>;
+
+    fn poll(self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<Self::Output> {
+        // Safe because the futures are boxed, and we never pin the results.
+        let this = unsafe { Pin::get_unchecked_mut(self) };
+        let mut error = None;
+        for (future, result) in this.futures.iter_mut().zip(&mut this.results) {
+            let output = match future {
+                Some(f) => match f.as_mut().poll(lw) {
+                    Poll::Ready(output) => output,
+                    Poll::Pending => continue,
+                },
+                None => continue,
+            };
+            *future = None;
+            match output {
+                Ok(value) => *result = Some(value),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        if let Some(e) = error {
+            // Cancel the work which hasn't finished yet.
+            this.futures.clear();
+            return Poll::Ready(Err(e));
+        }
+        if this.futures.iter().any(Option::is_some) {
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(this.results.drain(..).map(Option::unwrap).collect()))
+    }
+}
+
+// Run `future`, catching any panic. Returns the future's output, or the panic
+// which stopped it.
+pub fn catch_unwind<F: Future>(future: F) -> CatchUnwind<F> {
+    CatchUnwind { future }
+}
+
+pub struct CatchUnwind<F> {
+    future: F,
+}
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = thread::Result<F::Output>;
+
+    fn poll(self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<Self::Output> {
+        // Safe because we never move `future` (see `Timeout` in `timeout.rs`).
+        let this = unsafe { Pin::get_unchecked_mut(self) };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        // `AssertUnwindSafe` promises that nothing is left half-changed if the
+        // future panics. That's true here since the future is never polled
+        // again after it panics.
+        match panic::catch_unwind(AssertUnwindSafe(|| future.poll(lw))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(panic) => Poll::Ready(Err(panic)),
+        }
+    }
+}
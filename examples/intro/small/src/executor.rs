@@ -0,0 +1,322 @@
+// A single-threaded executor, written from scratch.
+//
+// Up to now, we've used `futures::executor::block_on` to run our async
+// functions. This is a much simpler executor which does the same job, so you
+// can see exactly what happens.
+//
+// An executor has a collection of tasks (a task is a future which the executor
+// is responsible for running to completion), and a queue of the tasks which are
+// ready to make progress. It repeatedly takes a task from the queue and polls
+// it. If the task can't finish yet, its future will have given a waker to
+// whatever it is waiting for (e.g., the timer thread). When that thing is
+// ready, it calls `wake`, which puts the task back on the queue. If there are
+// no tasks in the queue, the executor parks its thread (i.e., goes to sleep)
+// until a task is woken.
+//
+// For example, when we run `async_concurrent`, there is one task whose future
+// is the `join!` of the four pieces of work. The first poll polls each piece of
+// work, which each start and give the task's waker to the timer thread. The
+// task returns `Poll::Pending` and the queue is empty, so the executor thread
+// sleeps. When each timeout elapses, the timer thread wakes the task. The
+// executor polls it again, `join!` polls each unfinished piece of work, the
+// pieces of work whose timeouts have elapsed finish, and once all four have
+// finished, so has the task.
+//
+// While the executor is running, tasks can spawn more tasks using `spawn`.
+//
+// Since all the tasks share one thread, a task which blocks (e.g., by calling
+// `thread::sleep` or `work::do_work`) stops every other task from making
+// progress. The executor can't stop a task from blocking, but it can notice
+// afterwards: with `detect_blocking`, it times every poll and reports any which
+// take too long.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{local_waker_from_nonlocal, Poll, Wake};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+use backtrace::Backtrace;
+
+use crate::task::{self, JoinHandle};
+
+thread_local! {
+    // The executor running on this thread, if any.
+    static CURRENT: RefCell<Option<Executor>> = RefCell::new(None);
+    // Whether the executor on this thread is polling a task and wants to know
+    // where it blocks (see `blocking`).
+    static WATCHING: Cell<bool> = Cell::new(false);
+    // Where the task being polled first blocked, if it has.
+    static BLOCKED_AT: RefCell<Option<Backtrace>> = RefCell::new(None);
+}
+
+// Run `future` to completion on the current thread.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    Executor::new().block_on(future)
+}
+
+// Code which blocks the thread calls this first (see `timer::sleep`). If our
+// executor is polling a task on this thread and recording backtraces, it
+// remembers where the task blocked, so that it can say so in its report.
+pub fn blocking() {
+    if WATCHING.with(Cell::get) {
+        BLOCKED_AT.with(|at| {
+            let mut at = at.borrow_mut();
+            if at.is_none() {
+                *at = Some(Backtrace::new());
+            }
+        });
+    }
+}
+
+// Spawn a task on the executor running on this thread. Returns a handle which
+// can be awaited for the task's result.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    CURRENT.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .expect("`spawn` called outside of an executor")
+            .spawn(future)
+    })
+}
+
+// A handle to an executor. Cloning the handle gives another handle to the same
+// executor.
+#[derive(Clone)]
+pub struct Executor {
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    // The futures of spawned tasks which have not finished yet, by task id.
+    tasks: RefCell<HashMap<usize, Task>>,
+    queue: Arc<ReadyQueue>,
+    next_id: Cell<usize>,
+    blocking: Cell<Option<BlockingDetector>>,
+}
+
+// Settings for reporting polls which block the executor.
+#[derive(Clone, Copy)]
+struct BlockingDetector {
+    // Polls which take longer than this are reported.
+    threshold: Duration,
+    // Whether to record a backtrace where each task blocks.
+    backtraces: bool,
+}
+
+// The future passed to `block_on` is polled just like a task, using this id.
+const MAIN_TASK: usize = 0;
+
+struct Task {
+    future: Pin<Box<dyn Future<Output = ()>>>,
+    waker: Arc<TaskWaker>,
+}
+
+// The ids of tasks which are ready to be polled.
+struct ReadyQueue {
+    ready: Mutex<VecDeque<usize>>,
+    // The executor's thread, so we can wake it up when a task is ready.
+    thread: Thread,
+}
+
+impl ReadyQueue {
+    fn push(&self, id: usize) {
+        self.ready.lock().unwrap().push_back(id);
+        self.thread.unpark();
+    }
+
+    fn pop(&self) -> Option<usize> {
+        self.ready.lock().unwrap().pop_front()
+    }
+}
+
+// Each task has its own waker, which knows the task's id and which queue to
+// put it on. Wakers may be called from any thread (e.g., the timer thread), so
+// this must be thread-safe even though the executor isn't.
+struct TaskWaker {
+    id: usize,
+    queue: Arc<ReadyQueue>,
+    // Whether the task is already in the queue. A task might be woken many
+    // times before it is polled, but it only needs polling once.
+    queued: AtomicBool,
+}
+
+impl Wake for TaskWaker {
+    fn wake(arc_self: &Arc<Self>) {
+        if !arc_self.queued.swap(true, Ordering::SeqCst) {
+            arc_self.queue.push(arc_self.id);
+        }
+    }
+}
+
+// Poll `future` with the waker for its task.
+fn poll_with<F: Future + ?Sized>(waker: &Arc<TaskWaker>, future: Pin<&mut F>) -> Poll<F::Output> {
+    // The task is no longer in the queue, so if it is woken while we poll it, it
+    // must be queued again.
+    waker.queued.store(false, Ordering::SeqCst);
+    let lw = local_waker_from_nonlocal(waker.clone());
+    future.poll(&lw)
+}
+
+impl Executor {
+    pub fn new() -> Executor {
+        Executor {
+            inner: Rc::new(Inner {
+                tasks: RefCell::new(HashMap::new()),
+                queue: Arc::new(ReadyQueue {
+                    ready: Mutex::new(VecDeque::new()),
+                    thread: thread::current(),
+                }),
+                next_id: Cell::new(MAIN_TASK + 1),
+                blocking: Cell::new(None),
+            }),
+        }
+    }
+
+    // Report every poll which takes longer than `threshold`. If `backtraces` is
+    // true, the report includes a backtrace of where the task blocked. By the
+    // time a poll returns, whatever blocked has finished, so we can only find
+    // out where if the blocking code tells us, like `timer::sleep` (and so
+    // `work::do_work`) does. A task which blocks some other way, e.g., by
+    // computing for a long time, is reported without a backtrace.
+    pub fn detect_blocking(&self, threshold: Duration, backtraces: bool) {
+        self.inner.blocking.set(Some(BlockingDetector {
+            threshold,
+            backtraces,
+        }));
+    }
+
+    // Add a task to the executor. It will be run while the executor is running
+    // (i.e., during `block_on`).
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let inner = &self.inner;
+        let id = inner.next_id.get();
+        inner.next_id.set(id + 1);
+
+        let (future, handle) = task::spawnable(future);
+        let waker = inner.waker(id);
+        inner.tasks.borrow_mut().insert(
+            id,
+            Task {
+                future: Box::pin(future),
+                waker: waker.clone(),
+            },
+        );
+        // A new task is ready to be polled straight away.
+        TaskWaker::wake(&waker);
+        handle
+    }
+
+    // Run tasks until `future` is complete, and return its result. Tasks which
+    // haven't finished by then are dropped when the executor is.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let inner = &self.inner;
+        assert!(
+            inner.queue.thread.id() == thread::current().id(),
+            "an executor must be run on the thread which created it"
+        );
+
+        // Make this the current executor, so tasks can call `spawn`. We put the
+        // previous one back when we're done, even if a task panics.
+        struct Reset(Option<Executor>);
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+            }
+        }
+        let previous = CURRENT.with(|current| current.borrow_mut().replace(self.clone()));
+        let _reset = Reset(previous);
+
+        // The future must not move once it has been polled, so we pin it in a
+        // box.
+        let mut future = Box::pin(future);
+        let main_waker = inner.waker(MAIN_TASK);
+        TaskWaker::wake(&main_waker);
+
+        loop {
+            match inner.queue.pop() {
+                Some(MAIN_TASK) => {
+                    let result = inner.poll_checked(MAIN_TASK, &main_waker, future.as_mut());
+                    if let Poll::Ready(output) = result {
+                        return output;
+                    }
+                }
+                Some(id) => inner.poll_task(id),
+                // Nothing to do. Sleep until a waker unparks us. `park` can
+                // return spuriously, that's fine since we'll just find the
+                // queue is still empty and park again.
+                None => thread::park(),
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn poll_task(&self, id: usize) {
+        // We take the task out of the map while we poll it, so that the task
+        // can spawn other tasks (which need to borrow the map).
+        let mut task = match self.tasks.borrow_mut().remove(&id) {
+            Some(task) => task,
+            // A task which has finished might still be woken.
+            None => return,
+        };
+
+        if let Poll::Pending = self.poll_checked(id, &task.waker, task.future.as_mut()) {
+            self.tasks.borrow_mut().insert(id, task);
+        }
+    }
+
+    // Poll task `id`, and report the poll if it took too long.
+    fn poll_checked<F: Future + ?Sized>(
+        &self,
+        id: usize,
+        waker: &Arc<TaskWaker>,
+        future: Pin<&mut F>,
+    ) -> Poll<F::Output> {
+        let detector = match self.blocking.get() {
+            Some(detector) => detector,
+            None => return poll_with(waker, future),
+        };
+
+        let watching = WATCHING.with(|w| w.replace(detector.backtraces));
+        let start = Instant::now();
+        let result = poll_with(waker, future);
+        let elapsed = start.elapsed();
+        WATCHING.with(|w| w.set(watching));
+        let blocked_at = BLOCKED_AT.with(|at| at.borrow_mut().take());
+
+        if elapsed > detector.threshold {
+            if id == MAIN_TASK {
+                message!("blocking: the main task was polled for {:?}", elapsed);
+            } else {
+                message!("blocking: task {} was polled for {:?}", id, elapsed);
+            }
+            if let Some(backtrace) = blocked_at {
+                message!("blocked at:\n{:?}", backtrace);
+            }
+        }
+        result
+    }
+
+    fn waker(&self, id: usize) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
+            id,
+            queue: self.queue.clone(),
+            queued: AtomicBool::new(false),
+        })
+    }
+}
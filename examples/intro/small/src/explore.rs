@@ -0,0 +1,193 @@
+// A deterministic scheduler, for exploring the orders in which tasks can run.
+//
+// When several tasks are ready at once, the order an executor polls them in is
+// an accident of how it is written (ours uses a queue, so it is the order they
+// were woken in, which for the timer is the order the wheel happens to give us
+// its timeouts). A bug which only shows up in one order might turn up once in a
+// hundred runs, and there is no way to get it back.
+//
+// This scheduler runs a set of tasks on a virtual clock (see `clock.rs`) and
+// makes each choice deliberately. Whenever more than one task is ready, it asks
+// the `Schedule` which one to poll next: either pick at random from a seed, so
+// the same seed always gives the same order, or follow an explicit list of
+// tasks. Every run returns the choices it made, which can be passed back as an
+// explicit schedule to replay the run exactly.
+//
+// Since each run is just a sequence of choices, we can also try every possible
+// sequence (see `explore`), and check that something holds however the tasks
+// are interleaved. This only works for a small number of tasks - four tasks
+// which are each polled twice can be run in 576 different orders.
+
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{local_waker_from_nonlocal, Poll, Wake};
+use std::vec;
+
+use crate::clock::VirtualClock;
+use crate::rng::Rng;
+use crate::timer::{self, Timer};
+
+pub type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+// How to choose which ready task to poll next. Tasks are numbered from zero, in
+// the order they are passed to `run`.
+pub enum Schedule {
+    // Pick at random, using this seed.
+    Seed(u64),
+    // Poll the tasks in this order. Each one must be ready when its turn comes.
+    // Once the list runs out, poll the lowest numbered ready task.
+    Explicit(Vec<usize>),
+}
+
+// A choice made by the scheduler.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Step {
+    // The tasks which were ready, lowest numbered first.
+    pub ready: Vec<usize>,
+    // The task which was polled.
+    pub polled: usize,
+}
+
+// The order in which `steps` polled the tasks, as a schedule which will repeat
+// the run.
+pub fn replay(steps: &[Step]) -> Schedule {
+    Schedule::Explicit(steps.iter().map(|step| step.polled).collect())
+}
+
+// Run `tasks` to completion, using `schedule` to choose which task to poll
+// whenever more than one is ready. When none are ready, the clock jumps straight
+// to the next timeout. Returns every choice made along the way.
+pub fn run(tasks: Vec<Task>, schedule: Schedule) -> Vec<Step> {
+    let clock = Arc::new(VirtualClock::new());
+    let timer = Timer::new(clock.clone());
+    let mut chooser = match schedule {
+        Schedule::Seed(seed) => Chooser::Random(Rng::new(seed)),
+        Schedule::Explicit(order) => Chooser::Explicit(order.into_iter()),
+    };
+
+    // Every task is ready to be polled to start with.
+    let ready = Arc::new(Mutex::new((0..tasks.len()).collect::<BTreeSet<_>>()));
+    // `None` once a task has finished.
+    let mut tasks: Vec<Option<Task>> = tasks.into_iter().map(Some).collect();
+    let mut remaining = tasks.len();
+    let mut steps = Vec::new();
+
+    timer::with_timer(&timer, || {
+        while remaining > 0 {
+            // A finished task might still be woken, so we ignore those.
+            let candidates: Vec<usize> = ready
+                .lock()
+                .unwrap()
+                .iter()
+                .cloned()
+                .filter(|&id| tasks[id].is_some())
+                .collect();
+
+            if candidates.is_empty() {
+                let deadline = timer.next_deadline().expect("tasks are stuck");
+                let now = timer::now();
+                if deadline > now {
+                    clock.advance(deadline - now);
+                }
+                timer.turn();
+                continue;
+            }
+
+            let id = chooser.choose(&candidates);
+            steps.push(Step {
+                ready: candidates,
+                polled: id,
+            });
+
+            // The task is no longer ready, if it is woken while we poll it, it
+            // will be ready again.
+            ready.lock().unwrap().remove(&id);
+            let lw = local_waker_from_nonlocal(Arc::new(TaskWaker {
+                id,
+                ready: ready.clone(),
+            }));
+            let finished = match tasks[id] {
+                Some(ref mut task) => task.as_mut().poll(&lw).is_ready(),
+                None => unreachable!(),
+            };
+            if finished {
+                tasks[id] = None;
+                remaining -= 1;
+            }
+        }
+    });
+
+    steps
+}
+
+// Run the tasks made by `make_tasks` once with every possible schedule, calling
+// `check` with the choices made after each run. Returns the number of runs.
+//
+// We search depth first. The first run always polls the lowest numbered ready
+// task. After each run, we find the last choice where a higher numbered task
+// was ready, and the next run makes the same choices up to there, then picks
+// that task instead.
+pub fn explore(
+    mut make_tasks: impl FnMut() -> Vec<Task>,
+    mut check: impl FnMut(&[Step]),
+) -> usize {
+    let mut schedule = Vec::new();
+    let mut runs = 0;
+    loop {
+        let steps = run(make_tasks(), Schedule::Explicit(schedule));
+        check(&steps);
+        runs += 1;
+
+        let last = steps
+            .iter()
+            .rposition(|step| step.ready.last() != Some(&step.polled));
+        match last {
+            Some(i) => {
+                schedule = steps[..i].iter().map(|step| step.polled).collect();
+                let step = &steps[i];
+                let next = step.ready.iter().position(|&id| id == step.polled).unwrap() + 1;
+                schedule.push(step.ready[next]);
+            }
+            None => return runs,
+        }
+    }
+}
+
+enum Chooser {
+    Random(Rng),
+    Explicit(vec::IntoIter<usize>),
+}
+
+impl Chooser {
+    fn choose(&mut self, ready: &[usize]) -> usize {
+        match self {
+            Chooser::Random(rng) => ready[rng.below(ready.len() as u64) as usize],
+            Chooser::Explicit(order) => match order.next() {
+                Some(id) => {
+                    assert!(
+                        ready.contains(&id),
+                        "task {} is not ready (ready: {:?})",
+                        id,
+                        ready
+                    );
+                    id
+                }
+                None => ready[0],
+            },
+        }
+    }
+}
+
+// Marks a task as ready when it is woken.
+struct TaskWaker {
+    id: usize,
+    ready: Arc<Mutex<BTreeSet<usize>>>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(arc_self: &Arc<Self>) {
+        arc_self.ready.lock().unwrap().insert(arc_self.id);
+    }
+}
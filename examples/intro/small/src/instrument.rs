@@ -0,0 +1,163 @@
+// An executor which keeps statistics about its tasks.
+//
+// `InstrumentedExecutor` wraps our single-threaded executor. Each spawned task
+// is wrapped in an `Instrumented` future, which counts how many times the task
+// is polled and how long each poll takes. It also wraps the task's waker, so
+// that we know when the task was woken and can measure how long the task waited
+// between being woken and being polled.
+//
+// A task which is polled many times, or spends a long time in `poll`, is doing
+// something wrong - a task should be polled once to start and then once each
+// time it is woken, and each poll should be quick. A task which waits a long
+// time between being woken and being polled means the executor is too busy
+// (maybe because another task is doing one of those wrong things).
+
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{local_waker_from_nonlocal, LocalWaker, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
+
+use crate::executor::Executor;
+use crate::task::JoinHandle;
+
+pub struct InstrumentedExecutor {
+    executor: Executor,
+    // The name and statistics of each task, in the order they were spawned.
+    tasks: RefCell<Vec<(String, Arc<Mutex<Stats>>)>>,
+}
+
+// What we know about a task.
+#[derive(Clone, Debug)]
+pub struct Stats {
+    // The number of times the task was polled.
+    pub polls: u64,
+    // The total time spent polling the task.
+    pub busy: Duration,
+    // The total time between the task being woken and being polled.
+    pub waiting: Duration,
+    // When the task was last woken, if it hasn't been polled since.
+    woken_at: Option<Instant>,
+}
+
+impl InstrumentedExecutor {
+    pub fn new() -> InstrumentedExecutor {
+        InstrumentedExecutor {
+            executor: Executor::new(),
+            tasks: RefCell::new(Vec::new()),
+        }
+    }
+
+    // Spawn a task, recording its statistics under `name`.
+    pub fn spawn<F>(&self, name: &str, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let stats = Arc::new(Mutex::new(Stats {
+            polls: 0,
+            busy: Duration::from_millis(0),
+            waiting: Duration::from_millis(0),
+            // A new task is ready to be polled, so it starts off waiting.
+            woken_at: Some(Instant::now()),
+        }));
+        self.tasks.borrow_mut().push((name.to_owned(), stats.clone()));
+        self.executor.spawn(Instrumented { future, stats })
+    }
+
+    // Run the executor until `future` is complete.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.executor.block_on(future)
+    }
+
+    // The statistics for each task spawned so far, in the order they were
+    // spawned.
+    pub fn stats(&self) -> Vec<(String, Stats)> {
+        self.tasks
+            .borrow()
+            .iter()
+            .map(|(name, stats)| (name.clone(), stats.lock().unwrap().clone()))
+            .collect()
+    }
+
+    // A table of the statistics for each task.
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{:>4} {:<24} {:>8} {:>14} {:>14}",
+            "task", "name", "polls", "busy", "waiting"
+        )
+        .unwrap();
+        for (i, (name, stats)) in self.stats().into_iter().enumerate() {
+            writeln!(
+                out,
+                "{:>4} {:<24} {:>8} {:>14?} {:>14?}",
+                i + 1,
+                name,
+                stats.polls,
+                stats.busy,
+                stats.waiting
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+// A future which records statistics about the future it wraps.
+struct Instrumented<F> {
+    future: F,
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<F::Output> {
+        // Safe because we never move `future` (see `Timeout` in `timeout.rs`).
+        let this = unsafe { Pin::get_unchecked_mut(self) };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        let start = Instant::now();
+        {
+            let mut stats = this.stats.lock().unwrap();
+            stats.polls += 1;
+            if let Some(woken_at) = stats.woken_at.take() {
+                stats.waiting += start - woken_at;
+            }
+        }
+
+        // Whatever the future is waiting for will wake our waker, which notes
+        // the time and then wakes the executor's waker.
+        let waker = Arc::new(InstrumentedWaker {
+            inner: lw.clone().into_waker(),
+            stats: this.stats.clone(),
+        });
+        let result = future.poll(&local_waker_from_nonlocal(waker));
+
+        this.stats.lock().unwrap().busy += start.elapsed();
+        result
+    }
+}
+
+struct InstrumentedWaker {
+    inner: Waker,
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl Wake for InstrumentedWaker {
+    fn wake(arc_self: &Arc<Self>) {
+        {
+            let mut stats = arc_self.stats.lock().unwrap();
+            // If we're woken several times before we're polled, we've been
+            // waiting since the first time.
+            if stats.woken_at.is_none() {
+                stats.woken_at = Some(Instant::now());
+            }
+        }
+        arc_self.inner.wake();
+    }
+}
@@ -0,0 +1,109 @@
+// Doing something periodically.
+//
+// An `Interval` is a stream which yields every `period`. A stream is like a
+// future which can produce many values, one after another. Each call to
+// `poll_next` either produces the next value, says that there are no more
+// values, or (just like a future) returns `Poll::Pending` having arranged for
+// the task to be woken when it is worth asking again.
+//
+// If the task consuming the stream is busy, it might not ask for a tick until
+// after the next tick was due, or even several ticks later. What should happen
+// then is up to the user, see `MissedTicks`.
+
+use futures::stream::Stream;
+
+use std::pin::Pin;
+use std::task::{LocalWaker, Poll};
+use std::time::{Duration, Instant};
+
+use crate::timer::{self, Registration};
+
+// What to do when we fall behind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissedTicks {
+    // Yield all the missed ticks straight away, then carry on with the original
+    // schedule.
+    Burst,
+    // Forget about the missed ticks and carry on with the original schedule.
+    Skip,
+    // Yield one tick straight away, then carry on a period after that.
+    Delay,
+}
+
+pub struct Interval {
+    period: Duration,
+    missed: MissedTicks,
+    // When the next tick is due.
+    next: Instant,
+    // A timeout for `next`.
+    timeout: Registration,
+}
+
+impl Interval {
+    // An interval which first ticks after one period, and bursts to catch up on
+    // any missed ticks.
+    pub fn new(period: Duration) -> Interval {
+        Interval::with_missed_ticks(period, MissedTicks::Burst)
+    }
+
+    pub fn with_missed_ticks(period: Duration, missed: MissedTicks) -> Interval {
+        assert!(period > Duration::from_millis(0), "period must be non-zero");
+        let next = timer::now() + period;
+        Interval {
+            period,
+            missed,
+            next,
+            timeout: Registration::at(next),
+        }
+    }
+
+    // When the tick after `tick` is due, given that it is now `now`.
+    fn after(&self, tick: Instant, now: Instant) -> Instant {
+        let next = tick + self.period;
+        if next > now {
+            return next;
+        }
+
+        // We've missed at least one tick.
+        match self.missed {
+            MissedTicks::Burst => next,
+            MissedTicks::Skip => {
+                let missed = nanos(now - tick) / nanos(self.period);
+                tick + self.period * (missed as u32 + 1)
+            }
+            MissedTicks::Delay => now + self.period,
+        }
+    }
+}
+
+impl Stream for Interval {
+    // Each item is the instant the tick was due (which may be a bit earlier
+    // than when it is received).
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<Option<Instant>> {
+        // Like `Delay`, an `Interval` is safe to move.
+        let this = &mut *self;
+
+        // If we're behind, the tick is due already and there's no need to wait
+        // for the timer.
+        if timer::now() < this.next {
+            if let Poll::Pending = this.timeout.poll_elapsed(lw) {
+                return Poll::Pending;
+            }
+        }
+
+        let tick = this.next;
+        this.next = this.after(tick, timer::now());
+        // Replacing the registration drops the old one, removing it from the
+        // timer if it hasn't fired yet.
+        this.timeout = Registration::at(this.next);
+
+        // An interval never ends, so we never return `None`.
+        Poll::Ready(Some(tick))
+    }
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos())
+}
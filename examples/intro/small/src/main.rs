@@ -1,61 +1,984 @@
 #![feature(async_await, await_macro, futures_api, generators)]
 
-use futures::executor::block_on;
+use futures::future::poll_fn;
 use futures::join;
+use futures::stream::StreamExt;
 
+use std::collections::HashSet;
+use std::env;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
 use std::thread;
+use std::time::Duration;
+
+use crate::cli::{ExecutorKind, Format, Options};
+use crate::clock::VirtualClock;
+use crate::executor::{block_on, Executor};
+use crate::instrument::InstrumentedExecutor;
+use crate::interval::{Interval, MissedTicks};
+use crate::pool::ThreadPool;
+use crate::semaphore::Semaphore;
+use crate::timer::Timer;
+use crate::work::{Failures, WorkError};
+
+// Print a message from a model, like `println!`. With `--format json`, stdout
+// is kept for the JSON, so messages go to stderr instead (see `show`).
+macro_rules! message {
+    ($($arg:tt)*) => {
+        if crate::MESSAGES_TO_STDERR.load(std::sync::atomic::Ordering::SeqCst) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+// Whether `message!` prints to stderr.
+static MESSAGES_TO_STDERR: AtomicBool = AtomicBool::new(false);
 
 // Functions that will do some long-running work.
 mod work;
+// Runs async functions.
+mod executor;
+// Runs async functions on many threads.
+mod pool;
+// Handles to spawned tasks.
+mod task;
+// An executor which keeps statistics about its tasks.
+mod instrument;
+// A scheduler which chooses the order tasks run in.
+mod explore;
+// Combining futures.
+mod combinator;
+// Child tasks which can't outlive their parent.
+mod scope;
+// Limiting how much work is in flight at once.
+mod semaphore;
+// Sending values between tasks.
+mod channel;
+// Recording what the work does.
+mod trace;
+// Drawing timelines of the work.
+mod timeline;
+// A single thread which handles the timeouts for all work.
+mod timer;
+// Where the timer gets the time from.
+mod clock;
+// Giving up on work which takes too long.
+mod timeout;
+// Doing things periodically.
+mod interval;
+// The data structure the timer uses to keep track of timeouts.
+mod wheel;
+// Benchmarks, run with `cargo run --release -- bench-timers` or
+// `cargo run --release -- bench-models`.
+mod bench;
+// Random numbers for jitter.
+mod rng;
+// Parsing the command line.
+mod cli;
+// Helpers for polling futures by hand in tests.
+#[cfg(test)]
+mod testing;
 
 // For each model of computation, we'll run four tasks rather than two from the
-// text so there is more opportunity to see reorderings. You'll still probably
-// need to run the examples several times.
+// text so there is more opportunity to see reorderings (or as many as you like,
+// e.g., `cargo run -- --tasks 10`). Each task takes the corresponding time from
+// `d`. If they all take the same time, you'll probably need to run the examples
+// several times to see reorderings; pass a seed (e.g., `cargo run -- --seed 42`)
+// to give each task a different, but repeatable, duration. See `cli.rs` for all
+// the options.
+
+// If a piece of work panics, say so, rather than letting the panic stop the
+// whole model.
+fn report_panic(x: i32, result: thread::Result<()>) {
+    if let Err(panic) = result {
+        message!("work {} panicked: {}", x, task::panic_message(&*panic));
+    }
+}
+
+fn sequential(d: &[Duration]) {
+    // `catch_unwind` stops a panic in the closure, and gives it to us as an
+    // error. `AssertUnwindSafe` promises that nothing is left half-changed by
+    // the panic, which is true since the work doesn't change anything.
+    let isolate = |x: i32, duration| {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| work::do_work(x, duration)));
+        report_panic(x, result);
+    };
+    for (x, &d) in (1..).zip(d) {
+        isolate(x, d);
+    }
+}
+
+fn multi_threaded(d: &[Duration]) {
+    let threads: Vec<_> = (1..)
+        .zip(d)
+        .map(|(x, &d)| thread::spawn(move || work::do_work(x, d)))
+        .collect();
+
+    // A panic stops the thread it happens on, and `join` returns it as an
+    // error.
+    for (x, t) in (1..).zip(threads) {
+        report_panic(x, t.join());
+    }
+}
+
+// Do a piece of work, and if it panics, report it here. A panic would
+// otherwise stop the whole task (see `task.rs`), including the other work in
+// `async_seq` or `async_concurrent`, which all runs in one task. Just like
+// `sequential`, we catch the panic around each piece of work instead.
+async fn isolated_work(x: i32, d: Duration) {
+    report_panic(x, await!(combinator::catch_unwind(work::do_work_async(x, d))));
+}
+
+// `async_seq` and `async_concurrent` are the examples from the text, with four
+// pieces of work rather than two. With any other number of pieces of work
+// (`--tasks`), we can't write them out one by one, so we use a loop instead.
+async fn async_seq(d: &[Duration]) {
+    if let [d1, d2, d3, d4] = *d {
+        await!(isolated_work(1, d1));
+        await!(isolated_work(2, d2));
+        await!(isolated_work(3, d3));
+        await!(isolated_work(4, d4));
+    } else {
+        for (x, &d) in (1..).zip(d) {
+            await!(isolated_work(x, d));
+        }
+    }
+}
+
+async fn async_concurrent(d: &[Duration]) {
+    if let [d1, d2, d3, d4] = *d {
+        let f1 = isolated_work(1, d1);
+        let f2 = isolated_work(2, d2);
+        let f3 = isolated_work(3, d3);
+        let f4 = isolated_work(4, d4);
+        join!(f1, f2, f3, f4);
+    } else {
+        // `join!` needs to know how many futures there are when we write the
+        // code. `join_all` (see `combinator.rs`) does the same for any number.
+        let work = (1..).zip(d).map(|(x, &d)| isolated_work(x, d)).collect();
+        await!(combinator::join_all(work));
+    }
+}
+
+// Asynchronous and multi-threaded. Each piece of work is a separate task, and
+// the tasks are run by a pool of threads. Any thread in the pool can poll any
+// task, so a piece of work might start on one thread and finish on another
+// (look at the thread ids, or the last letter of each row in the timeline).
+fn async_multi_threaded(d: &[Duration]) {
+    let pool = ThreadPool::new(4);
+    let handles: Vec<_> = (1..)
+        .zip(d)
+        .map(|(x, &d)| pool.spawn(work::do_work_async(x, d)))
+        .collect();
+    pool.shutdown_on_idle();
+
+    // Every task has finished, so getting the results doesn't block. A task
+    // which panicked has an error saying why.
+    for (x, handle) in (1..).zip(handles) {
+        if let Err(e) = block_on(handle) {
+            message!("work {}: {}", x, e);
+        }
+    }
+}
+
+// Spawn a task for each piece of work, then collect their results. We can't use
+// `join!` because we don't know how many pieces of work there will be until the
+// program runs. Instead, we keep the `JoinHandle` for each task
+// and await them one by one. The tasks all run concurrently, so waiting for
+// one doesn't stop the others making progress.
+async fn async_spawn(d: &[Duration]) {
+    let n = d.len();
+    let handles: Vec<_> = (1..)
+        .zip(&d[..n - 1])
+        .map(|(x, &d)| {
+            executor::spawn(async move {
+                await!(work::do_work_async(x, d));
+                d
+            })
+        })
+        .collect();
+
+    // We don't want the result of the last piece of work, so we detach its
+    // task rather than keeping the handle. A detached task still runs to the
+    // end, we just can't await it. So that we can see it finish, it tells us
+    // over a channel (see `channel.rs`). If it panics instead, the sender is
+    // dropped without sending anything.
+    let (done_tx, mut done_rx) = channel::bounded(1);
+    let d = d[n - 1];
+    executor::spawn(async move {
+        await!(work::do_work_async(n as i32, d));
+        await!(done_tx.send(())).unwrap();
+    })
+    .detach();
+
+    // We change our mind about the first piece of work. An aborted task is
+    // never polled again, so it never finishes its work.
+    if let Some(first) = handles.first() {
+        first.abort();
+    }
+
+    for (x, handle) in handles.into_iter().enumerate() {
+        match await!(handle) {
+            Ok(d) => message!("work {} took {:?}", x + 1, d),
+            Err(e) => message!("work {}: {}", x + 1, e),
+        }
+    }
+
+    // Tasks which are still running when `block_on` returns are dropped, so we
+    // wait to hear from the detached task before we finish.
+    match await!(done_rx.recv()) {
+        Some(()) => message!("work {} was detached, and finished anyway", n),
+        None => message!("work {} was detached, and didn't finish", n),
+    }
+}
+
+// The same as `async_concurrent`, but using the version of the work which
+// waits on a hand-written `Delay` future.
+async fn async_concurrent_delay(d: &[Duration]) {
+    let work = (1..).zip(d).map(|(x, &d)| work::do_work_async_delay(x, d)).collect();
+    await!(combinator::join_all(work));
+}
+
+// Run `model`, printing messages as usual, then draw a timeline of the work.
+fn with_timeline(name: &str, model: impl FnOnce()) {
+    let events = Arc::new(trace::Memory::new());
+    trace::with_recorder(Arc::new(trace::Tee(Arc::new(trace::Stdout), events.clone())), model);
+
+    println!("\n{}:", name);
+    println!("{}", timeline::render(&events.events()));
+}
+
+// Start work `x`, but give up on it if it hasn't finished after `limit`.
+// Returns whether the work finished.
+async fn work_or_cancel(x: i32, limit: Duration) -> bool {
+    // We need to poll the work ourselves, so it must be pinned. Putting it in a
+    // `Box` means it will never move.
+    let mut work = Some(Box::pin(work::do_work_async(x, work::WORK_DURATION)));
+    let mut limit = work::Delay::new(limit);
+
+    await!(poll_fn(|lw| {
+        if let Poll::Ready(()) = work.as_mut().unwrap().as_mut().poll(lw) {
+            return Poll::Ready(true);
+        }
+        if let Poll::Ready(()) = Pin::new(&mut limit).poll(lw) {
+            // Cancelling a future is just dropping it: it will never be polled
+            // again, and dropping it removes its timeout from the timer.
+            message!("cancelling work {}", x);
+            work = None;
+            return Poll::Ready(false);
+        }
+        Poll::Pending
+    }))
+}
+
+// Start four tasks, and cancel two of them half way through their work.
+async fn async_cancel() {
+    let (r1, r2, r3, r4) = join!(
+        work_or_cancel(1, Duration::from_secs(1)),
+        work_or_cancel(2, Duration::from_millis(250)),
+        work_or_cancel(3, Duration::from_secs(1)),
+        work_or_cancel(4, Duration::from_millis(250)),
+    );
+
+    for &(x, finished) in &[(1, r1), (2, r2), (3, r3), (4, r4)] {
+        if !finished {
+            message!("work {} never reached \"work done!\"", x);
+        }
+    }
+    // None of the cancelled work left a timeout behind.
+    message!("timeouts still pending: {}", timer::pending());
+}
+
+// Race four pieces of work which take different times. The first to finish
+// wins, and the others are dropped straight away. They started, but none of them
+// reach "work done!" (look for "(never finished)" in the timeline).
+async fn async_race() {
+    let durations = [750, 250, 500, 1000];
+    let work: Vec<_> = durations
+        .iter()
+        .zip(1..)
+        .map(|(&millis, x)| work::do_work_async(x, Duration::from_millis(millis)))
+        .collect();
+
+    let (winner, ()) = await!(combinator::race(work));
+    message!("work {} won the race", winner + 1);
+    for x in (1..=durations.len()).filter(|&x| x != winner + 1) {
+        message!("work {} was dropped before it finished", x);
+    }
+    // Dropping the losers removed their timeouts from the timer.
+    message!("timeouts still pending: {}", timer::pending());
+}
+
+// Spawn four pieces of work as child tasks in a scope. Work 2 fails as soon as
+// it is done, so the scope cancels the other children and returns work 2's
+// error. By the time the scope returns, none of its children are left running.
+async fn async_scope() {
+    let result: Result<(), String> = await!(scope::scope(|s| async move {
+        for &(x, millis) in &[(1, 1000), (2, 250), (3, 750), (4, 500)] {
+            s.spawn(async move {
+                await!(work::do_work_async(x, Duration::from_millis(millis)));
+                if x == 2 {
+                    return Err(format!("work {} failed", x));
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }));
+
+    match result {
+        Ok(()) => message!("every piece of work finished"),
+        Err(e) => message!("the scope failed: {}", e),
+    }
+    message!("timeouts still pending: {}", timer::pending());
+}
+
+// Do pieces of fallible work in sequence, adding up their results. `?` returns
+// the first error from the function straight away, so after a piece of work
+// fails, the rest never start.
+fn try_sequential(d: &[Duration], failures: &Failures) -> Result<i32, WorkError> {
+    let mut total = 0;
+    for (x, &d) in (1..).zip(d) {
+        total += work::try_do_work(x, d, failures.fails(x))?;
+    }
+    Ok(total)
+}
+
+// The same, but async. `?` works across `await!` just like it does in
+// synchronous code.
+async fn async_try_seq(d: &[Duration], failures: &Failures) -> Result<i32, WorkError> {
+    let mut total = 0;
+    for (x, &d) in (1..).zip(d) {
+        total += await!(work::try_do_work_async(x, d, failures.fails(x)))?;
+    }
+    Ok(total)
+}
+
+// The same work, but concurrently. When a piece of work fails, `try_join`
+// cancels the others, which never reach "work done!".
+async fn async_try_join(d: &[Duration], failures: &Failures) -> Result<i32, WorkError> {
+    let work = (1..)
+        .zip(d)
+        .map(|(x, &d)| work::try_do_work_async(x, d, failures.fails(x)))
+        .collect();
+    let results = await!(combinator::try_join(work))?;
+    Ok(results.iter().sum())
+}
+
+// Do `n` pieces of work, with at most `k` in flight at once. Returns how long
+// it took, and the most pieces of work which were in flight at once.
+async fn limited(n: i32, k: usize, duration: Duration) -> (Duration, usize) {
+    let semaphore = Semaphore::new(k);
+    let in_flight = AtomicUsize::new(0);
+    let most_in_flight = AtomicUsize::new(0);
 
-fn sequential() {
-    work::do_work(1);
-    work::do_work(2);
-    work::do_work(3);
-    work::do_work(4);
+    let start = timer::now();
+    let work = (1..=n)
+        .map(|x| {
+            let semaphore = &semaphore;
+            let in_flight = &in_flight;
+            let most_in_flight = &most_in_flight;
+            async move {
+                // Hold the permit until the work is done.
+                let _permit = await!(semaphore.acquire());
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                if now > most_in_flight.load(Ordering::SeqCst) {
+                    most_in_flight.store(now, Ordering::SeqCst);
+                }
+                await!(work::do_work_async(x, duration));
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            }
+        })
+        .collect();
+    await!(combinator::join_all(work));
+
+    // Every permit has been given back.
+    assert_eq!(semaphore.available_permits(), k);
+    (timer::now() - start, most_in_flight.load(Ordering::SeqCst))
+}
+
+// Do 100 pieces of work (or `--tasks`), limiting how many are in flight at once
+// with a semaphore, for different limits. With a limit of `k`, the work is done
+// in batches of `k`, so it takes about `100 / k` times as long as one piece of
+// work. The work is short, so this doesn't take too long.
+async fn async_semaphore(n: usize) {
+    let duration = Duration::from_millis(20);
+    for &k in &[1, 2, 5, 10, 25, 50, 100] {
+        let (time, most) = await!(limited(n as i32, k, duration));
+        message!(
+            "{:>4} pieces of work, at most {:>3} at once: {:>3} in flight at most, took {:?}",
+            n, k, most, time
+        );
+    }
+}
+
+// A pipeline: a producer hands out `n` work ids, three workers do the work, and
+// a collector gathers up the results. Each worker has its own job channel,
+// which holds one id, and they all send their results to the collector down
+// one results channel.
+//
+// The collector is slow, it takes 300ms over each result, and the workers only
+// take 100ms over each piece of work. If the results channel is bounded, it
+// soon fills up, then the workers have to wait to send their results, their
+// job channels fill up, and the producer has to wait too. That's backpressure:
+// the whole pipeline slows down to the collector's pace, and you can see the
+// producer start to produce at that pace. If the results channel is unbounded,
+// nothing waits to send: the producer and workers race ahead, and the results
+// pile up in the channel waiting for the collector.
+async fn async_pipeline(n: usize, bounded: bool) {
+    const WORKERS: usize = 3;
+    let start = timer::now();
+    let millis = move || timer::millis(timer::now() - start);
+
+    let (results_tx, mut results_rx) = if bounded {
+        channel::bounded(2)
+    } else {
+        channel::unbounded()
+    };
+    let mut jobs = Vec::new();
+    let mut workers = Vec::new();
+    for _ in 0..WORKERS {
+        let (job_tx, mut job_rx) = channel::bounded(1);
+        let results_tx = results_tx.clone();
+        jobs.push(job_tx);
+        workers.push(async move {
+            while let Some(x) = await!(job_rx.recv()) {
+                await!(work::do_work_async(x, Duration::from_millis(100)));
+                await!(results_tx.send(x)).unwrap();
+            }
+        });
+    }
+    // The collector stops once every worker has dropped its sender, so we
+    // mustn't keep one here.
+    drop(results_tx);
+
+    let producer = async move {
+        for x in 1..=n as i32 {
+            await!(jobs[(x as usize - 1) % WORKERS].send(x)).unwrap();
+            message!("{:>5}ms produced {}", millis(), x);
+        }
+        // `jobs` is dropped here, which tells the workers there is no more
+        // work.
+    };
+    let collector = async move {
+        let mut most_waiting = 0;
+        while let Some(x) = await!(results_rx.recv()) {
+            // Count the result we've just taken out, too.
+            let waiting = results_rx.len() + 1;
+            if waiting > most_waiting {
+                most_waiting = waiting;
+            }
+            await!(work::Delay::new(Duration::from_millis(300)));
+            message!("{:>5}ms collected {}", millis(), x);
+        }
+        most_waiting
+    };
+
+    let (_, _, most_waiting) = join!(producer, combinator::join_all(workers), collector);
+    message!("at most {} results were waiting for the collector", most_waiting);
+}
+
+// Give each piece of work a time limit of 500ms. Work which takes longer is
+// cancelled when its time is up, and we get `Err(Elapsed)` instead of its
+// result.
+async fn async_timeout() {
+    let limit = Duration::from_millis(500);
+    let (r1, r2, r3, r4) = join!(
+        timeout::with_timeout(work::do_work_async(1, Duration::from_millis(250)), limit),
+        timeout::with_timeout(work::do_work_async(2, Duration::from_millis(1000)), limit),
+        timeout::with_timeout(work::do_work_async(3, Duration::from_millis(450)), limit),
+        timeout::with_timeout(work::do_work_async(4, Duration::from_millis(550)), limit),
+    );
+
+    for &(x, result) in &[(1, r1), (2, r2), (3, r3), (4, r4)] {
+        match result {
+            Ok(()) => message!("work {} finished in time", x),
+            Err(e) => message!("work {}: {}", x, e),
+        }
+    }
+}
+
+// Tick five times, every 200ms. After the second tick, do a piece of work
+// which takes longer than the period, so some ticks are missed.
+async fn ticker(x: i32, missed: MissedTicks) {
+    let start = timer::now();
+    let period = Duration::from_millis(200);
+    // Bursting is what an interval does unless we say otherwise.
+    let mut interval = match missed {
+        MissedTicks::Burst => Interval::new(period),
+        _ => Interval::with_missed_ticks(period, missed),
+    };
+    for i in 1..=5 {
+        await!(interval.next());
+        let since = timer::now() - start;
+        message!("{:?} tick {} after {}ms", missed, i, timer::millis(since));
+
+        if i == 2 {
+            await!(work::do_work_async(x, work::WORK_DURATION));
+        }
+    }
+}
+
+// Periodic work alongside one-off work. The tickers and the work all run
+// concurrently on the same thread. Compare when each ticker ticks after falling
+// behind.
+async fn async_interval() {
+    join!(
+        ticker(1, MissedTicks::Burst),
+        ticker(2, MissedTicks::Skip),
+        ticker(3, MissedTicks::Delay),
+        work::do_work_async(4, work::WORK_DURATION),
+        work::do_work_async(5, work::WORK_DURATION),
+    );
+}
+
+// Time the models using a virtual clock, so that we don't have to wait and the
+// answer doesn't depend on how busy the computer is. Every time the models are
+// waiting, we move the clock on by the time one piece of work takes (one
+// 'tick'). Doing the work in sequence takes four ticks, doing it concurrently
+// only one. The tests at the bottom of this file check this.
+fn virtual_time() {
+    let d = [work::WORK_DURATION; 4];
+
+    let (_, ticks) = timer::run_with_virtual_clock(async_seq(&d), work::WORK_DURATION);
+    message!("async_seq took {} ticks", ticks);
+    let (_, ticks) = timer::run_with_virtual_clock(async_concurrent(&d), work::WORK_DURATION);
+    message!("async_concurrent took {} ticks", ticks);
+
+    // A synchronous sleep on a virtual clock just moves the clock forward.
+    let clock = Arc::new(VirtualClock::new());
+    timer::with_timer(&Timer::new(clock.clone()), || sequential(&d));
+    message!("sequential took {:?}", clock.elapsed());
+}
+
+// The four pieces of work from `async_concurrent`, as separate tasks for the
+// deterministic scheduler (see `explore.rs`).
+fn interleaving_tasks() -> Vec<explore::Task> {
+    (1..=4)
+        .map(|x| Box::pin(work::do_work_async(x, work::WORK_DURATION)) as explore::Task)
+        .collect()
+}
+
+// Run the four pieces of work from `async_concurrent` on the deterministic
+// scheduler. First we run them in an order chosen by `seed`; running with the
+// same seed, or replaying the choices it made, always gives the same order.
+// Then we run them in every possible order, and count the different orders the
+// work finishes in. The tests at the bottom of this file check that however
+// the work is interleaved, every piece of work starts before any finishes.
+fn interleavings(seed: u64) {
+    let events = Arc::new(trace::Memory::new());
+    let steps = trace::with_recorder(events.clone(), || {
+        explore::run(interleaving_tasks(), explore::Schedule::Seed(seed))
+    });
+    message!("seed {} ran the work in the order {:?}", seed, events.phases());
+    let choices: Vec<usize> = steps.iter().map(|step| step.polled).collect();
+    message!("the scheduler polled the tasks in the order {:?}", choices);
+
+    let events = Arc::new(trace::Memory::new());
+    let mut seen = 0;
+    let mut done_orders = HashSet::new();
+    let runs = trace::with_recorder(events.clone(), || {
+        explore::explore(interleaving_tasks, |_| {
+            let phases = events.phases();
+            let done: Vec<i32> = phases[seen..]
+                .iter()
+                .filter(|(_, phase)| *phase == trace::Phase::Done)
+                .map(|(x, _)| *x)
+                .collect();
+            seen = phases.len();
+            done_orders.insert(done);
+        })
+    });
+    message!(
+        "explored {} interleavings, the work finished in {} different orders",
+        runs,
+        done_orders.len()
+    );
 }
 
-fn multi_threaded() {
-    let t1 = thread::spawn(|| work::do_work(1));
-    let t2 = thread::spawn(|| work::do_work(2));
-    let t3 = thread::spawn(|| work::do_work(3));
-    let t4 = thread::spawn(|| work::do_work(4));
+// Compare how hard the executor has to work when a task spins waiting for its
+// timeout, and when it waits for the timer thread to wake it up. The busy task
+// is polled thousands of times, the other only twice: once to start it and once
+// when the timer wakes it.
+fn wakeups() {
+    // Forget any polls from earlier models.
+    work::take_poll_count();
 
-    t1.join().unwrap();
-    t2.join().unwrap();
-    t3.join().unwrap();
-    t4.join().unwrap();
+    block_on(work::do_work_async_busy(1, work::WORK_DURATION));
+    message!("busy waiting: polled {} times", work::take_poll_count());
+
+    block_on(work::do_work_async(2, work::WORK_DURATION));
+    message!("using a waker: polled {} times", work::take_poll_count());
+}
+
+// A common mistake: calling blocking code from async code. `do_work` puts the
+// thread to sleep, and since our executor runs every task on the same thread,
+// no other task can make progress until it wakes up. Here, work 2 doesn't even
+// start until work 1 is done. The executor reports the poll which blocked; set
+// `RUST_BACKTRACE=1` to see where it blocked, too.
+fn async_blocking() {
+    let executor = Executor::new();
+    let backtraces = env::var("RUST_BACKTRACE").map_or(false, |v| v != "0");
+    executor.detect_blocking(Duration::from_millis(100), backtraces);
+
+    let h1 = executor.spawn(async { work::do_work(1, work::WORK_DURATION) });
+    let h2 = executor.spawn(work::do_work_async(2, work::WORK_DURATION));
+    executor.block_on(async {
+        join!(h1, h2);
+    });
+}
+
+// Run each kind of work as a task on an instrumented executor, then print how
+// many times each task was polled, how long it spent being polled, and how long
+// it waited to be polled after being woken. The busy-waiting task stands out
+// straight away.
+fn instrumented() {
+    let executor = InstrumentedExecutor::new();
+    let d = work::WORK_DURATION;
+    let h1 = executor.spawn("do_work_async", work::do_work_async(1, d));
+    let h2 = executor.spawn("do_work_async_delay", work::do_work_async_delay(2, d));
+    let h3 = executor.spawn("do_work_async_busy", work::do_work_async_busy(3, d));
+    executor.block_on(async {
+        join!(h1, h2, h3);
+    });
+
+    message!("{}", executor.report());
 }
 
-async fn async_seq() {
-    await!(work::do_work_async(1));
-    await!(work::do_work_async(2));
-    await!(work::do_work_async(3));
-    await!(work::do_work_async(4));
+// Run the models again, but with work 2 panicking as soon as it starts. Each
+// model catches the panic and reports it, and the other work carries on. In
+// `sequential`, `async_seq` and `async_concurrent`, we catch the panic around
+// each piece of work. In `multi_threaded`, the panic stops its thread, and in
+// `async_spawn` and `async_multi_threaded`, it stops its task (see `task.rs`),
+// so we find out when we join the thread or await the task.
+fn panics(d: &[Duration], format: Format) {
+    work::set_panicking(Some(2));
+    show("sequential", format, || sequential(d));
+    show("multi_threaded", format, || multi_threaded(d));
+    show("async_seq", format, || block_on(async_seq(d)));
+    show("async_concurrent", format, || block_on(async_concurrent(d)));
+    show("async_spawn", format, || block_on(async_spawn(d)));
+    show("async_multi_threaded", format, || async_multi_threaded(d));
+    work::set_panicking(None);
 }
 
-async fn async_concurrent() {
-    let f1 = work::do_work_async(1);
-    let f2 = work::do_work_async(2);
-    let f3 = work::do_work_async(3);
-    let f4 = work::do_work_async(4);
-    join!(f1, f2, f3, f4);
+// Run `model`, showing the work it does in `format`.
+fn show(name: &str, format: Format, model: impl FnOnce()) {
+    match format {
+        Format::Text => model(),
+        Format::Timeline => with_timeline(name, model),
+        Format::Json => {
+            let events = Arc::new(trace::Memory::new());
+            let previous = MESSAGES_TO_STDERR.swap(true, Ordering::SeqCst);
+            trace::with_recorder(events.clone(), model);
+            MESSAGES_TO_STDERR.store(previous, Ordering::SeqCst);
+            println!("{}", trace::to_json(name, &events.events()));
+        }
+    }
 }
 
-// It's easiest to see what is happening if you comment out all but one function
-// call.
+// Run an async model to completion on the chosen executor. The asynchronous
+// models require us to block on the result to ensure we wait for it to be
+// executed. We can't use `await` here since `main` is not an async function.
+//
+// The models catch panics in their work, but if anything else panics, we
+// report it rather than letting it stop the program.
+fn run_async(executor: ExecutorKind, model: impl Future<Output = ()> + Send + 'static) {
+    let result = match executor {
+        ExecutorKind::Single => block_on(combinator::catch_unwind(model)),
+        ExecutorKind::Pool(size) => {
+            let pool = ThreadPool::new(size);
+            let handle = pool.spawn(model);
+            pool.shutdown_on_idle();
+            // A task on the pool catches its own panics (see `task.rs`).
+            if let Err(e) = block_on(handle) {
+                message!("{}", e);
+            }
+            Ok(())
+        }
+        ExecutorKind::Futures => futures::executor::block_on(combinator::catch_unwind(model)),
+    };
+    if let Err(panic) = result {
+        message!("the model panicked: {}", task::panic_message(&*panic));
+    }
+}
+
+// The models from the text, which are run if none are chosen.
+const DEFAULT_MODELS: &[&str] = &["sequential", "multi_threaded", "async_seq", "async_concurrent"];
+
+// The models which can be run from the command line (see `--list`).
+const MODELS: &[(&str, &str)] = &[
+    ("sequential", "do each piece of work in turn, blocking the thread"),
+    ("multi_threaded", "do each piece of work on its own thread"),
+    ("async_seq", "await each piece of work in turn"),
+    ("async_concurrent", "await all the work at once, on one thread"),
+    ("async_multi_threaded", "run each piece of work as a task on a thread pool"),
+    ("async_concurrent_delay", "async_concurrent, using a hand-written future"),
+    ("async_spawn", "spawn a task for each piece of work, abort one and detach one"),
+    ("async_cancel", "give up on work which takes too long"),
+    ("async_race", "race work against each other, dropping the losers"),
+    ("async_scope", "run work as child tasks in a scope, where one fails"),
+    ("try_sequential", "do fallible work in turn, stopping at the first error"),
+    ("async_try_seq", "try_sequential, awaiting each piece of work"),
+    ("async_try_join", "do fallible work at once, cancelling the rest on an error"),
+    ("async_semaphore", "run 100 pieces of work, limiting how many are in flight"),
+    ("async_pipeline", "pass work through channels, with a slow consumer"),
+    ("async_timeout", "give each piece of work a time limit"),
+    ("async_interval", "do periodic work alongside one-off work"),
+    ("panics", "the main models again, where a piece of work panics"),
+    ("wakeups", "compare busy-waiting with using a waker"),
+    ("instrumented", "count the polls of and time spent in each task"),
+    ("async_blocking", "catch blocking work in an async task"),
+    ("virtual_time", "check the models' timing using a virtual clock"),
+    ("interleavings", "run the work in every possible order"),
+];
+
+// These are only run if they're chosen.
+const BENCHMARKS: &[(&str, &str)] = &[
+    ("bench-timers", "compare the timer's wheel with a heap"),
+    ("bench-models", "compare the models with many tasks"),
+];
+
+fn run_model(name: &str, options: &Options) {
+    let seed = options.seed;
+    let d = work::durations(seed, options.tasks.unwrap_or(4), options.duration);
+    let executor = options.executor;
+    // Each model is shown in `format` if one was chosen, or its own default.
+    let format = |default| options.format.unwrap_or(default);
+    // Without a seed, work 3 fails. With a seed, each piece of work has a one
+    // in four chance of failing.
+    let failures = seed.map_or(Failures::Only(vec![3]), |seed| Failures::Random {
+        seed,
+        percent: 25,
+    });
+
+    // For the main models, we draw a timeline of the work, so you can see how
+    // the models differ at a glance.
+    match name {
+        "sequential" => show(name, format(Format::Timeline), || sequential(&d)),
+        "multi_threaded" => show(name, format(Format::Timeline), || multi_threaded(&d)),
+        "async_seq" => show(name, format(Format::Timeline), || {
+            run_async(executor, async move { await!(async_seq(&d)) })
+        }),
+        "async_concurrent" => show(name, format(Format::Timeline), || {
+            run_async(executor, async move { await!(async_concurrent(&d)) })
+        }),
+        "async_multi_threaded" => {
+            show(name, format(Format::Timeline), || async_multi_threaded(&d))
+        }
+        "async_concurrent_delay" => show(name, format(Format::Text), || {
+            run_async(executor, async move { await!(async_concurrent_delay(&d)) })
+        }),
+        // The number of tasks isn't known until the program is running. Unless
+        // it is chosen with `--tasks`, it is chosen randomly if there is a
+        // seed. Spawning needs our own executor.
+        "async_spawn" => {
+            let n = options.tasks.unwrap_or_else(|| {
+                seed.map_or(6, |seed| 2 + rng::Rng::new(seed).below(8) as usize)
+            });
+            let d = work::durations(seed, n, options.duration);
+            show(name, format(Format::Text), || block_on(async_spawn(&d)))
+        }
+        "async_cancel" => show(name, format(Format::Text), || run_async(executor, async_cancel())),
+        "async_race" => show(name, format(Format::Timeline), || run_async(executor, async_race())),
+        "async_scope" => show(name, format(Format::Timeline), || block_on(async_scope())),
+        "try_sequential" => show(name, format(Format::Timeline), || {
+            message!("try_sequential: {:?}", try_sequential(&d, &failures))
+        }),
+        "async_try_seq" => show(name, format(Format::Timeline), || {
+            run_async(executor, async move {
+                message!("async_try_seq: {:?}", await!(async_try_seq(&d, &failures)))
+            })
+        }),
+        "async_try_join" => show(name, format(Format::Timeline), || {
+            run_async(executor, async move {
+                message!("async_try_join: {:?}", await!(async_try_join(&d, &failures)))
+            })
+        }),
+        // Printing messages for hundreds of pieces of work would hide the
+        // results, so by default we don't.
+        "async_semaphore" => {
+            let n = options.tasks.unwrap_or(100);
+            let model = || run_async(executor, async_semaphore(n));
+            match options.format {
+                Some(format) => show(name, format, model),
+                None => trace::with_recorder(Arc::new(trace::Discard), model),
+            }
+        }
+        // The same pipeline twice, to compare a bounded results channel with an
+        // unbounded one.
+        "async_pipeline" => {
+            let n = options.tasks.unwrap_or(12);
+            show("async_pipeline (bounded)", format(Format::Timeline), || {
+                run_async(executor, async_pipeline(n, true))
+            });
+            show("async_pipeline (unbounded)", format(Format::Timeline), || {
+                run_async(executor, async_pipeline(n, false))
+            });
+        }
+        "async_timeout" => {
+            show(name, format(Format::Text), || run_async(executor, async_timeout()))
+        }
+        "async_interval" => {
+            show(name, format(Format::Text), || run_async(executor, async_interval()))
+        }
+        "panics" => panics(&d, format(Format::Timeline)),
+        "wakeups" => show(name, format(Format::Text), wakeups),
+        "instrumented" => show(name, format(Format::Text), instrumented),
+        "async_blocking" => show(name, format(Format::Text), async_blocking),
+        "virtual_time" => show(name, format(Format::Text), virtual_time),
+        "interleavings" => show(name, format(Format::Text), || interleavings(seed.unwrap_or(0))),
+        "bench-timers" => bench::timers(),
+        "bench-models" => bench::models(),
+        _ => unreachable!("unknown model {}", name),
+    }
+}
+
+// It's easiest to see what is happening if you run one model at a time, e.g.,
+// `cargo run -- async_concurrent`.
 fn main() {
-    sequential();
+    let names: Vec<&str> = MODELS
+        .iter()
+        .chain(BENCHMARKS.iter())
+        .map(|&(name, _)| name)
+        .collect();
+    let options = match cli::parse(env::args().skip(1), &names) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    if options.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    if options.list {
+        for &(name, about) in MODELS.iter().chain(BENCHMARKS.iter()) {
+            println!("{:24} {}", name, about);
+        }
+        return;
+    }
+
+    if options.models.is_empty() {
+        for name in DEFAULT_MODELS {
+            run_model(name, &options);
+        }
+    } else {
+        for name in &options.models {
+            run_model(name, &options);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::Phase::{Done, Start};
+
+    const D: [Duration; 4] = [work::WORK_DURATION; 4];
+
+    #[test]
+    fn async_seq_takes_four_ticks() {
+        let _lock = trace::test_lock();
+        let events = Arc::new(trace::Memory::new());
+        let (_, ticks) = trace::with_recorder(events.clone(), || {
+            timer::run_with_virtual_clock(async_seq(&D), work::WORK_DURATION)
+        });
+        assert_eq!(ticks, 4);
+        assert_eq!(
+            events.phases(),
+            vec![
+                (1, Start),
+                (1, Done),
+                (2, Start),
+                (2, Done),
+                (3, Start),
+                (3, Done),
+                (4, Start),
+                (4, Done),
+            ]
+        );
+    }
+
+    #[test]
+    fn async_concurrent_takes_one_tick() {
+        let _lock = trace::test_lock();
+        let events = Arc::new(trace::Memory::new());
+        let (_, ticks) = trace::with_recorder(events.clone(), || {
+            timer::run_with_virtual_clock(async_concurrent(&D), work::WORK_DURATION)
+        });
+        assert_eq!(ticks, 1);
+        // Every piece of work starts before any finishes.
+        let phases: Vec<_> = events.phases().into_iter().map(|(_, phase)| phase).collect();
+        assert_eq!(phases, vec![Start, Start, Start, Start, Done, Done, Done, Done]);
+    }
+
+    #[test]
+    fn sequential_takes_four_ticks() {
+        let _lock = trace::test_lock();
+        let clock = Arc::new(VirtualClock::new());
+        trace::with_recorder(Arc::new(trace::Discard), || {
+            timer::with_timer(&Timer::new(clock.clone()), || sequential(&D))
+        });
+        assert_eq!(clock.elapsed(), work::WORK_DURATION * 4);
+    }
+
+    #[test]
+    fn replaying_a_seed_gives_the_same_order() {
+        let _lock = trace::test_lock();
+        for seed in 0..10 {
+            let events = Arc::new(trace::Memory::new());
+            let steps = trace::with_recorder(events.clone(), || {
+                explore::run(interleaving_tasks(), explore::Schedule::Seed(seed))
+            });
+            let first = events.phases();
+
+            let events = Arc::new(trace::Memory::new());
+            let replayed = trace::with_recorder(events.clone(), || {
+                explore::run(interleaving_tasks(), explore::replay(&steps))
+            });
+            assert_eq!(events.phases(), first);
+            assert_eq!(replayed, steps);
+        }
+    }
 
-    multi_threaded();
+    // However the work is interleaved, every piece of work starts before any
+    // finishes, and each one finishes exactly once.
+    #[test]
+    fn every_interleaving_starts_all_the_work_first() {
+        let _lock = trace::test_lock();
+        let events = Arc::new(trace::Memory::new());
+        let mut seen = 0;
+        let mut done_orders = HashSet::new();
+        let runs = trace::with_recorder(events.clone(), || {
+            explore::explore(interleaving_tasks, |_| {
+                let phases = events.phases();
+                let run = &phases[seen..];
+                seen = phases.len();
 
-    // The asynchronous versions require us to block on the result to ensure we
-    // wait for it to be executed. We can't use `await` here since `main` is not
-    // an async function.
-    block_on(async_seq());
-    block_on(async_concurrent());
+                let starts = run.iter().take_while(|(_, phase)| *phase == Start).count();
+                assert_eq!(starts, 4);
+                let mut done: Vec<i32> = run
+                    .iter()
+                    .filter(|(_, phase)| *phase == Done)
+                    .map(|(x, _)| *x)
+                    .collect();
+                done_orders.insert(done.clone());
+                done.sort();
+                assert_eq!(done, vec![1, 2, 3, 4]);
+            })
+        });
+        assert_eq!(runs, 576);
+        // The work can finish in any order.
+        assert_eq!(done_orders.len(), 24);
+    }
 }
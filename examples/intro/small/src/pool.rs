@@ -0,0 +1,237 @@
+// A multi-threaded, work-stealing executor.
+//
+// The executor in `executor.rs` runs all its tasks on one thread. This one has
+// a pool of worker threads, and any worker can run any task. Since a task is
+// only run when it is woken, and it can be woken from anywhere, the same task
+// might be polled on a different thread each time.
+//
+// Each worker has its own queue (a deque, i.e., a double-ended queue) of ready
+// tasks. When a task is woken by a worker (e.g., a task spawns another task),
+// it goes on that worker's queue. When a task is woken from some other thread
+// (e.g., by the timer thread), it goes on a global queue, the 'injector'. A
+// worker looking for something to do first checks its own queue, then the
+// injector, and if both are empty it tries to steal a task from another
+// worker's queue. Workers take from the back of their own queue, but steal from
+// the front of others', so a worker and a thief only compete when there is a
+// single task in the queue.
+//
+// Having a queue per worker means workers don't usually compete for the same
+// lock, and work-stealing means no worker is idle while others have work
+// waiting. Real work-stealing executors (e.g., Tokio's) use lock-free deques;
+// we use a `Mutex` around a `VecDeque` to keep things simple.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{local_waker_from_nonlocal, Poll, Wake};
+use std::thread;
+
+use crate::task::{self, JoinHandle};
+
+thread_local! {
+    // If this thread is a worker, the address of its pool's `Shared` and the
+    // worker's index.
+    static WORKER: Cell<Option<(usize, usize)>> = Cell::new(None);
+}
+
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+struct Shared {
+    // Tasks woken from outside the pool.
+    injector: Mutex<VecDeque<Arc<Task>>>,
+    // Each worker's queue.
+    queues: Vec<Mutex<VecDeque<Arc<Task>>>>,
+
+    // Workers with nothing to do wait on `wake_worker`.
+    sleep: Mutex<()>,
+    wake_worker: Condvar,
+    shutdown: AtomicBool,
+
+    // The number of tasks which have been spawned but not finished. We notify
+    // `idle` when it gets to zero.
+    active: Mutex<usize>,
+    idle: Condvar,
+}
+
+struct Task {
+    // `None` once the task has finished.
+    future: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    shared: Arc<Shared>,
+    // Whether the task is in a queue, so we don't queue it twice.
+    queued: AtomicBool,
+}
+
+impl Wake for Task {
+    fn wake(arc_self: &Arc<Self>) {
+        if !arc_self.queued.swap(true, Ordering::SeqCst) {
+            arc_self.shared.schedule(arc_self.clone());
+        }
+    }
+}
+
+impl ThreadPool {
+    // Start a pool with `size` worker threads.
+    pub fn new(size: usize) -> ThreadPool {
+        assert!(size > 0, "a pool needs at least one worker");
+        let shared = Arc::new(Shared {
+            injector: Mutex::new(VecDeque::new()),
+            queues: (0..size).map(|_| Mutex::new(VecDeque::new())).collect(),
+            sleep: Mutex::new(()),
+            wake_worker: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+            active: Mutex::new(0),
+            idle: Condvar::new(),
+        });
+
+        let workers = (0..size)
+            .map(|index| {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name(format!("worker-{}", index))
+                    .spawn(move || shared.run_worker(index))
+                    .expect("could not start a worker thread")
+            })
+            .collect();
+
+        ThreadPool { shared, workers }
+    }
+
+    // Add a task to the pool. The future must be `Send` since it may be moved
+    // between threads.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (future, handle) = task::spawnable(future);
+        *self.shared.active.lock().unwrap() += 1;
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(future))),
+            shared: self.shared.clone(),
+            queued: AtomicBool::new(false),
+        });
+        Task::wake(&task);
+        handle
+    }
+
+    // Wait until every task has finished, then stop the workers.
+    pub fn shutdown_on_idle(self) {
+        let mut active = self.shared.active.lock().unwrap();
+        while *active > 0 {
+            active = self.shared.idle.wait(active).unwrap();
+        }
+        // Dropping the pool stops the workers.
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        {
+            let _sleep = self.shared.sleep.lock().unwrap();
+            self.shared.wake_worker.notify_all();
+        }
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+impl Shared {
+    // Put a woken task in a queue.
+    fn schedule(&self, task: Arc<Task>) {
+        match self.worker_index() {
+            Some(index) => self.queues[index].lock().unwrap().push_back(task),
+            None => self.injector.lock().unwrap().push_back(task),
+        }
+
+        // Taking the lock means we can't notify a worker between it checking
+        // for work and going to sleep, which would leave it asleep with work
+        // waiting.
+        let _sleep = self.sleep.lock().unwrap();
+        self.wake_worker.notify_one();
+    }
+
+    // If the current thread is one of our workers, its index.
+    fn worker_index(&self) -> Option<usize> {
+        WORKER.with(|worker| match worker.get() {
+            Some((pool, index)) if pool == self as *const Shared as usize => Some(index),
+            _ => None,
+        })
+    }
+
+    fn run_worker(&self, index: usize) {
+        WORKER.with(|worker| worker.set(Some((self as *const Shared as usize, index))));
+
+        loop {
+            match self.find_task(index) {
+                Some(task) => self.run_task(task),
+                None => {
+                    let sleep = self.sleep.lock().unwrap();
+                    if self.shutdown.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    // Check again now we have the lock, a task might have been
+                    // scheduled since we looked.
+                    if !self.has_work() {
+                        drop(self.wake_worker.wait(sleep).unwrap());
+                    }
+                }
+            }
+        }
+    }
+
+    fn find_task(&self, index: usize) -> Option<Arc<Task>> {
+        // Our own queue first, newest task first.
+        if let Some(task) = self.queues[index].lock().unwrap().pop_back() {
+            return Some(task);
+        }
+        // Then tasks from outside the pool, oldest first.
+        if let Some(task) = self.injector.lock().unwrap().pop_front() {
+            return Some(task);
+        }
+        // Then steal the oldest task from another worker, starting with our
+        // neighbour so that workers don't all try to steal from the same place.
+        let count = self.queues.len();
+        (1..count)
+            .map(|offset| (index + offset) % count)
+            .filter_map(|victim| self.queues[victim].lock().unwrap().pop_front())
+            .next()
+    }
+
+    fn has_work(&self) -> bool {
+        !self.injector.lock().unwrap().is_empty()
+            || self.queues.iter().any(|q| !q.lock().unwrap().is_empty())
+    }
+
+    fn run_task(&self, task: Arc<Task>) {
+        let mut slot = task.future.lock().unwrap();
+        // The task is no longer queued, if it is woken from now on it must be
+        // queued again (even while we're polling it).
+        task.queued.store(false, Ordering::SeqCst);
+
+        let finished = match slot.as_mut() {
+            Some(future) => {
+                let lw = local_waker_from_nonlocal(task.clone());
+                future.as_mut().poll(&lw).is_ready()
+            }
+            // The task was woken after it finished.
+            None => return,
+        };
+
+        if finished {
+            *slot = None;
+            let mut active = self.active.lock().unwrap();
+            *active -= 1;
+            if *active == 0 {
+                self.idle.notify_all();
+            }
+        }
+    }
+}
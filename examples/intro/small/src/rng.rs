@@ -0,0 +1,68 @@
+// A small pseudo-random number generator (xorshift64*).
+//
+// We don't need good randomness, only numbers which look random enough and
+// which are always the same for the same seed, so that a run can be repeated
+// exactly.
+
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // Mix the seed with one step of splitmix64, so that similar seeds (e.g.,
+        // 42 and 43) give very different sequences. The mixing never maps two
+        // seeds to the same state.
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        // The state must never be zero, or it would stay zero forever. Only one
+        // seed mixes to zero, so it can have an arbitrary state instead.
+        Rng {
+            state: if z == 0 { 0x2545_f491_4f6c_dd1d } else { z },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // A number in `0..n`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        for _ in 0..10 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+    }
+
+    // Neighbouring seeds used to share a state.
+    #[test]
+    fn neighbouring_seeds_differ() {
+        for seed in 0..100 {
+            assert_ne!(Rng::new(seed).next_u64(), Rng::new(seed + 1).next_u64());
+        }
+    }
+
+    #[test]
+    fn below_is_in_range() {
+        let mut rng = Rng::new(7);
+        for _ in 0..1000 {
+            assert!(rng.below(10) < 10);
+        }
+    }
+}
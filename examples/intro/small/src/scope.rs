@@ -0,0 +1,272 @@
+// Structured concurrency: tasks which can't outlive the code which spawned them.
+//
+// A task spawned with `executor::spawn` runs until it finishes, whatever
+// happens to the task which spawned it. If the spawner fails, or forgets about
+// the `JoinHandle`, the task carries on in the background, and if the task
+// fails, nobody might notice.
+//
+// `scope` gives the spawned tasks a parent. It calls its closure with a
+// `Scope`, which can spawn child tasks, and the future it returns doesn't
+// finish until the closure's future and every child have finished. If any of
+// them fails - returns an error or panics - the others are cancelled, and once
+// they're gone the scope fails with the same error (or panic). So when the
+// scope returns, there are no children left running, and no failure is lost.
+//
+// If the scope itself is dropped before it finishes, its children are aborted
+// straight away.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::future::Future;
+use std::panic;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{LocalWaker, Poll};
+use std::thread;
+
+use crate::combinator;
+use crate::executor;
+use crate::task::JoinHandle;
+
+// Run the future returned by `f` and any children it spawns using the `Scope`
+// it is given. Children are spawned on the current executor, see
+// `executor::spawn`.
+pub fn scope<T, E, F, Fut>(f: F) -> Scoped<Fut, E>
+where
+    F: FnOnce(Scope<E>) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let scope = Scope {
+        children: Rc::new(RefCell::new(Vec::new())),
+    };
+    Scoped {
+        body: Some(Box::pin(f(scope.clone()))),
+        output: None,
+        failure: None,
+        children: scope.children,
+    }
+}
+
+// A child's result, or the panic which stopped it.
+type Outcome<E> = thread::Result<Result<(), E>>;
+
+// Used to spawn children in a scope.
+pub struct Scope<E> {
+    children: Rc<RefCell<Vec<JoinHandle<Outcome<E>>>>>,
+}
+
+// We can't derive `Clone`, since that would require `E: Clone`.
+impl<E> Clone for Scope<E> {
+    fn clone(&self) -> Scope<E> {
+        Scope {
+            children: self.children.clone(),
+        }
+    }
+}
+
+impl<E: 'static> Scope<E> {
+    // Spawn a child task. If it returns an error or panics, the scope fails.
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = Result<(), E>> + 'static,
+    {
+        // The task would catch a panic anyway (see `task.rs`), but we want the
+        // panic itself, so that we can carry on panicking in the parent.
+        let handle = executor::spawn(combinator::catch_unwind(future));
+        self.children.borrow_mut().push(handle);
+    }
+}
+
+enum Failure<E> {
+    Error(E),
+    Panic(Box<dyn Any + Send>),
+}
+
+pub struct Scoped<Fut: Future, E> {
+    // The future returned by the closure, until it finishes or the scope fails.
+    body: Option<Pin<Box<Fut>>>,
+    // What the closure's future returned, if it succeeded (so this is always
+    // `Ok`).
+    output: Option<Fut::Output>,
+    // The first failure, if there has been one.
+    failure: Option<Failure<E>>,
+    // The children which haven't finished.
+    children: Rc<RefCell<Vec<JoinHandle<Outcome<E>>>>>,
+}
+
+impl<Fut: Future, E> Scoped<Fut, E> {
+    // Remember the first failure, and cancel everything else.
+    fn fail(&mut self, failure: Failure<E>) {
+        if self.failure.is_some() {
+            return;
+        }
+        self.failure = Some(failure);
+        self.body = None;
+        for child in self.children.borrow().iter() {
+            child.abort();
+        }
+    }
+}
+
+impl<T, E, Fut> Future for Scoped<Fut, E>
+where
+    Fut: Future<Output = Result<T, E>>,
+{
+    type Output = Result<T, E>;
+
+    fn poll(self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<Result<T, E>> {
+        // Safe because nothing in `Scoped` is pinned, the body is pinned in its
+        // own box.
+        let this = unsafe { Pin::get_unchecked_mut(self) };
+
+        let body_result = match this.body.as_mut() {
+            Some(body) => match body.as_mut().poll(lw) {
+                Poll::Ready(result) => Some(result),
+                Poll::Pending => None,
+            },
+            None => None,
+        };
+        match body_result {
+            Some(Ok(output)) => {
+                this.body = None;
+                this.output = Some(Ok(output));
+            }
+            Some(Err(e)) => this.fail(Failure::Error(e)),
+            None => {}
+        }
+
+        // Poll the children which haven't finished, forgetting about those
+        // which have. The body might have spawned more children since we were
+        // last polled, polling them now means we'll be woken when they finish.
+        let mut failures = Vec::new();
+        {
+            let mut children = this.children.borrow_mut();
+            let mut i = 0;
+            while i < children.len() {
+                let outcome = match Pin::new(&mut children[i]).poll(lw) {
+                    Poll::Ready(outcome) => outcome,
+                    Poll::Pending => {
+                        i += 1;
+                        continue;
+                    }
+                };
+                children.swap_remove(i);
+                match outcome {
+                    Ok(Ok(Ok(()))) => {}
+                    Ok(Ok(Err(e))) => failures.push(Failure::Error(e)),
+                    Ok(Err(panic)) => failures.push(Failure::Panic(panic)),
+                    // We aborted the child because something else failed.
+                    Err(_) => {}
+                }
+            }
+        }
+        // Aborting the other children needs the list of children, so we wait
+        // until we've finished with it.
+        for failure in failures {
+            this.fail(failure);
+        }
+
+        if this.body.is_some() || !this.children.borrow().is_empty() {
+            return Poll::Pending;
+        }
+        match this.failure.take() {
+            Some(Failure::Error(e)) => Poll::Ready(Err(e)),
+            // Carry on panicking in the parent, as if the child's panic had
+            // happened here.
+            Some(Failure::Panic(panic)) => panic::resume_unwind(panic),
+            None => Poll::Ready(this.output.take().expect("scope polled after finishing")),
+        }
+    }
+}
+
+impl<Fut: Future, E> Drop for Scoped<Fut, E> {
+    fn drop(&mut self) {
+        for child in self.children.borrow().iter() {
+            child.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::task;
+    use crate::timeout::{self, Elapsed};
+    use crate::timer;
+    use crate::trace::{self, Phase};
+    use crate::work::{self, WorkError};
+
+    // These tests use the default timer, which every test shares. The trace
+    // lock also keeps other tests from adding timeouts while we count them.
+
+    #[test]
+    fn an_error_aborts_the_other_children() {
+        let _lock = trace::test_lock();
+        let (result, pending) = trace::with_recorder(Arc::new(trace::Discard), || {
+            executor::block_on(async {
+                let result = await!(scope(|s: Scope<WorkError>| async move {
+                    s.spawn(async {
+                        await!(work::do_work_async(1, Duration::from_secs(10)));
+                        Ok(())
+                    });
+                    s.spawn(async {
+                        await!(work::try_do_work_async(2, Duration::from_millis(10), true))
+                            .map(|_| ())
+                    });
+                    Ok(())
+                }));
+                // The first child's timeout went when it was aborted.
+                (result, timer::pending())
+            })
+        });
+        assert_eq!(result, Err(WorkError { x: 2 }));
+        assert_eq!(pending, 0);
+    }
+
+    #[test]
+    fn a_panic_carries_on_in_the_parent() {
+        let result = panic::catch_unwind(|| {
+            executor::block_on(scope(|s: Scope<()>| async move {
+                s.spawn(async {
+                    let fail = true;
+                    if fail {
+                        panic!("the child panicked");
+                    }
+                    Ok(())
+                });
+                Ok(())
+            }))
+        });
+        let panic = result.unwrap_err();
+        assert_eq!(task::panic_message(&*panic), "the child panicked");
+    }
+
+    #[test]
+    fn dropping_a_scope_aborts_its_children() {
+        let _lock = trace::test_lock();
+        let events = Arc::new(trace::Memory::new());
+        let pending = trace::with_recorder(events.clone(), || {
+            executor::block_on(async {
+                let scoped = scope(|s: Scope<()>| async move {
+                    s.spawn(async {
+                        await!(work::do_work_async(1, Duration::from_secs(10)));
+                        Ok(())
+                    });
+                    await!(work::Delay::new(Duration::from_secs(10)));
+                    Ok(())
+                });
+                // The timeout drops the scope before it finishes.
+                let result = await!(timeout::with_timeout(scoped, Duration::from_millis(50)));
+                assert_eq!(result, Err(Elapsed));
+                // Give the executor a chance to drop the aborted child.
+                await!(work::Delay::new(Duration::from_millis(10)));
+                timer::pending()
+            })
+        });
+        assert_eq!(pending, 0);
+        assert_eq!(events.phases(), vec![(1, Phase::Start)]);
+    }
+}
@@ -0,0 +1,255 @@
+// Limiting how much work is in flight at once.
+//
+// `join!` (or `join_all`) starts all of its work at once. That's fine for four
+// pieces of work, but if each piece of work opens a connection or uses a lot of
+// memory, starting thousands at once can overwhelm whatever they're talking to.
+//
+// A semaphore holds a number of permits. Before starting a piece of work, we
+// `acquire` a permit, and when the work is done we drop the permit, which gives
+// it back. If there are no permits left, `acquire` waits until someone gives
+// one back, so there are never more pieces of work in flight than permits.
+//
+// Waiting tasks are queued, and a permit which is given back goes straight to
+// the task at the front of the queue, so a task can't wait forever while
+// others keep taking permits ahead of it.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{LocalWaker, Poll, Waker};
+
+pub struct Semaphore {
+    state: Mutex<State>,
+}
+
+struct State {
+    // Permits which aren't held by anyone. This is only ever more than zero if
+    // nobody is waiting.
+    permits: usize,
+    // Tasks waiting for a permit, oldest first.
+    waiters: VecDeque<Arc<Waiter>>,
+}
+
+struct Waiter {
+    // Set when a permit has been handed to this waiter.
+    granted: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            state: Mutex::new(State {
+                permits,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    // Wait for a permit. The permit is given back when it is dropped.
+    pub fn acquire(&self) -> Acquire {
+        Acquire {
+            semaphore: self,
+            waiter: None,
+        }
+    }
+
+    // The number of permits which could be acquired straight away.
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().unwrap().permits
+    }
+
+    // Give a permit back, handing it to the first waiter if there is one.
+    fn release(&self, state: &mut State) {
+        match state.waiters.pop_front() {
+            Some(waiter) => {
+                // We set the flag while holding the waiter's lock, so the waiter
+                // can't miss it between checking the flag and storing its waker.
+                let mut waker = waiter.waker.lock().unwrap();
+                waiter.granted.store(true, Ordering::SeqCst);
+                if let Some(waker) = waker.take() {
+                    waker.wake();
+                }
+            }
+            None => state.permits += 1,
+        }
+    }
+}
+
+// A future which completes with a permit.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    // Our place in the queue, once we've had to wait.
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Permit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<Permit<'a>> {
+        // `Acquire` doesn't contain any references to itself, so it is safe to
+        // move and we can treat the pinned reference as a plain `&mut`.
+        let this = &mut *self;
+        match this.waiter {
+            None => {
+                let mut state = this.semaphore.state.lock().unwrap();
+                if state.permits > 0 {
+                    state.permits -= 1;
+                    return Poll::Ready(Permit {
+                        semaphore: this.semaphore,
+                    });
+                }
+                let waiter = Arc::new(Waiter {
+                    granted: AtomicBool::new(false),
+                    waker: Mutex::new(Some(lw.clone().into_waker())),
+                });
+                state.waiters.push_back(waiter.clone());
+                this.waiter = Some(waiter);
+            }
+            Some(ref waiter) => {
+                let mut waker = waiter.waker.lock().unwrap();
+                if waiter.granted.load(Ordering::SeqCst) {
+                    drop(waker);
+                    this.waiter = None;
+                    return Poll::Ready(Permit {
+                        semaphore: this.semaphore,
+                    });
+                }
+                *waker = Some(lw.clone().into_waker());
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl<'a> Drop for Acquire<'a> {
+    fn drop(&mut self) {
+        // If we're dropped while waiting, leave the queue. If we were handed a
+        // permit but never took it, pass it on.
+        if let Some(waiter) = self.waiter.take() {
+            let mut state = self.semaphore.state.lock().unwrap();
+            if waiter.granted.load(Ordering::SeqCst) {
+                self.semaphore.release(&mut state);
+            } else {
+                state.waiters.retain(|w| !Arc::ptr_eq(w, &waiter));
+            }
+        }
+    }
+}
+
+// Permission to do a piece of work. Dropping it gives it back to the
+// semaphore.
+pub struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        let mut state = self.semaphore.state.lock().unwrap();
+        self.semaphore.release(&mut state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{poll, ready, Flag};
+
+    #[test]
+    fn permits_are_given_back() {
+        let semaphore = Semaphore::new(2);
+        let (_, lw) = Flag::new();
+        let mut a1 = semaphore.acquire();
+        let mut a2 = semaphore.acquire();
+        let p1 = ready(poll(&mut a1, &lw));
+        let p2 = ready(poll(&mut a2, &lw));
+        assert_eq!(semaphore.available_permits(), 0);
+
+        drop(p1);
+        assert_eq!(semaphore.available_permits(), 1);
+        drop(p2);
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+
+    #[test]
+    fn waiters_are_served_in_order() {
+        let semaphore = Semaphore::new(1);
+        let (_, lw1) = Flag::new();
+        let (flag2, lw2) = Flag::new();
+        let (flag3, lw3) = Flag::new();
+        let mut a1 = semaphore.acquire();
+        let mut a2 = semaphore.acquire();
+        let mut a3 = semaphore.acquire();
+
+        let p1 = ready(poll(&mut a1, &lw1));
+        assert!(poll(&mut a2, &lw2).is_pending());
+        assert!(poll(&mut a3, &lw3).is_pending());
+
+        // The permit goes to the first waiter, even if the second is polled
+        // before it.
+        drop(p1);
+        assert!(flag2.take());
+        assert!(!flag3.take());
+        assert!(poll(&mut a3, &lw3).is_pending());
+        assert_eq!(semaphore.available_permits(), 0);
+        let p2 = ready(poll(&mut a2, &lw2));
+
+        drop(p2);
+        assert!(flag3.take());
+        let p3 = ready(poll(&mut a3, &lw3));
+        drop(p3);
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+
+    // A waiter which is dropped after it was handed a permit, but before it
+    // took it, passes the permit on to the next waiter.
+    #[test]
+    fn dropped_waiter_passes_its_permit_on() {
+        let semaphore = Semaphore::new(1);
+        let (_, lw1) = Flag::new();
+        let (flag2, lw2) = Flag::new();
+        let (flag3, lw3) = Flag::new();
+        let mut a1 = semaphore.acquire();
+        let mut a2 = semaphore.acquire();
+        let mut a3 = semaphore.acquire();
+
+        let p1 = ready(poll(&mut a1, &lw1));
+        assert!(poll(&mut a2, &lw2).is_pending());
+        assert!(poll(&mut a3, &lw3).is_pending());
+
+        drop(p1);
+        assert!(flag2.take());
+        assert!(!flag3.take());
+        drop(a2);
+        assert!(flag3.take());
+        assert_eq!(semaphore.available_permits(), 0);
+
+        let p3 = ready(poll(&mut a3, &lw3));
+        drop(p3);
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+
+    // A waiter which is dropped before it was handed a permit leaves the
+    // queue, so the permit isn't handed to it.
+    #[test]
+    fn dropped_waiter_leaves_the_queue() {
+        let semaphore = Semaphore::new(1);
+        let (_, lw) = Flag::new();
+        let mut a1 = semaphore.acquire();
+        let mut a2 = semaphore.acquire();
+
+        let p1 = ready(poll(&mut a1, &lw));
+        assert!(poll(&mut a2, &lw).is_pending());
+        drop(a2);
+        assert!(semaphore.state.lock().unwrap().waiters.is_empty());
+
+        drop(p1);
+        assert_eq!(semaphore.available_permits(), 1);
+        let mut a3 = semaphore.acquire();
+        let p3 = ready(poll(&mut a3, &lw));
+        drop(p3);
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+}
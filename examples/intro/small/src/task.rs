@@ -0,0 +1,179 @@
+// Handles to spawned tasks.
+//
+// When we spawn a task, the executor owns it and runs it to completion. To get
+// the task's result, the spawner gets a `JoinHandle`, which is a future that
+// completes with the task's result when the task finishes. The handle can also
+// abort the task, or be detached (dropped) if we don't care about the result,
+// in which case the task keeps running.
+//
+// The executors don't run the spawned future directly. They run a `Spawned`
+// future which wraps it, and which passes the result to the handle. `Spawned`
+// and `JoinHandle` share some state, and each may need to wake the other: the
+// handle wakes the task when it is aborted, and the task wakes whoever is
+// waiting on the handle when it finishes.
+//
+// If the task panics, `Spawned` catches the panic and the handle gets an error
+// saying why. The panic stops at the task, so the executor and its other tasks
+// carry on as if the task had finished.
+
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{LocalWaker, Poll, Waker};
+
+// Wrap `future` so it can be spawned on an executor. Returns the wrapped future
+// for the executor and the handle for the spawner.
+pub fn spawnable<F: Future>(future: F) -> (Spawned<F>, JoinHandle<F::Output>) {
+    let shared = Arc::new(Mutex::new(State {
+        result: None,
+        finished: false,
+        aborted: false,
+        task_waker: None,
+        join_waker: None,
+    }));
+    let spawned = Spawned {
+        future,
+        shared: shared.clone(),
+    };
+    (spawned, JoinHandle { shared })
+}
+
+// Why a task didn't produce a result.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JoinError {
+    // The task was aborted using its `JoinHandle`.
+    Aborted,
+    // The task panicked, with this message.
+    Panicked(String),
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Aborted => write!(f, "task was aborted"),
+            JoinError::Panicked(message) => write!(f, "task panicked: {}", message),
+        }
+    }
+}
+
+impl Error for JoinError {}
+
+// The message a panic was started with. `panic!` with just a string literal
+// panics with a `&str`, with formatting it panics with a `String`.
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+struct State<T> {
+    // The task's result, until it is taken by the handle.
+    result: Option<Result<T, JoinError>>,
+    finished: bool,
+    aborted: bool,
+    // Wakes the task, so that it can notice it has been aborted.
+    task_waker: Option<Waker>,
+    // Wakes whoever is waiting on the handle.
+    join_waker: Option<Waker>,
+}
+
+impl<T> State<T> {
+    fn finish(&mut self, result: Result<T, JoinError>) {
+        self.result = Some(result);
+        self.finished = true;
+        if let Some(waker) = self.join_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+pub struct Spawned<F: Future> {
+    future: F,
+    shared: Arc<Mutex<State<F::Output>>>,
+}
+
+impl<F: Future> Future for Spawned<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<()> {
+        // Safe because we never move `future` (see `Timeout` in `timeout.rs`).
+        let this = unsafe { Pin::get_unchecked_mut(self) };
+
+        {
+            let mut state = this.shared.lock().unwrap();
+            if state.aborted {
+                // Finishing now means the executor will drop us, and the
+                // wrapped future with us.
+                state.finish(Err(JoinError::Aborted));
+                return Poll::Ready(());
+            }
+            state.task_waker = Some(lw.clone().into_waker());
+        }
+
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        // `AssertUnwindSafe` promises that nothing is left half-changed if the
+        // future panics. That's true here since we finish the task, so the
+        // future is never polled again.
+        match panic::catch_unwind(AssertUnwindSafe(|| future.poll(lw))) {
+            Ok(Poll::Ready(output)) => {
+                this.shared.lock().unwrap().finish(Ok(output));
+                Poll::Ready(())
+            }
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(panic) => {
+                let message = panic_message(&*panic);
+                this.shared.lock().unwrap().finish(Err(JoinError::Panicked(message)));
+                Poll::Ready(())
+            }
+        }
+    }
+}
+
+pub struct JoinHandle<T> {
+    shared: Arc<Mutex<State<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    // Stop the task. It won't be polled again, and the handle will complete
+    // with `Err(JoinError::Aborted)`. If the task has already finished, this
+    // does nothing.
+    pub fn abort(&self) {
+        let mut state = self.shared.lock().unwrap();
+        if state.finished {
+            return;
+        }
+        state.aborted = true;
+        // The executor only drops the task once it is polled and finishes, so
+        // wake it up.
+        if let Some(waker) = state.task_waker.take() {
+            waker.wake();
+        }
+    }
+
+    // Let the task run without waiting for its result. This is the same as
+    // dropping the handle, but makes it clear that we meant to.
+    pub fn detach(self) {}
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<Self::Output> {
+        let mut state = self.shared.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.join_waker = Some(lw.clone().into_waker());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+// Helpers for tests which poll futures by hand, so they can check what happens
+// between polls without needing an executor or a timer.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{local_waker_from_nonlocal, LocalWaker, Poll, Wake};
+
+// A waker which remembers whether it has been woken.
+pub struct Flag(AtomicBool);
+
+impl Flag {
+    // A flag, and a waker which sets it.
+    pub fn new() -> (Arc<Flag>, LocalWaker) {
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let lw = local_waker_from_nonlocal(flag.clone());
+        (flag, lw)
+    }
+
+    // Whether the flag has been woken since we last asked.
+    pub fn take(&self) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+impl Wake for Flag {
+    fn wake(arc_self: &Arc<Self>) {
+        arc_self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+// Poll `future` once.
+pub fn poll<F: Future + Unpin>(future: &mut F, lw: &LocalWaker) -> Poll<F::Output> {
+    Pin::new(future).poll(lw)
+}
+
+// The output of a poll which should have finished.
+pub fn ready<T>(poll: Poll<T>) -> T {
+    match poll {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("the future wasn't ready"),
+    }
+}
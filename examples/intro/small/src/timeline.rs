@@ -0,0 +1,109 @@
+// Drawing a timeline of recorded events.
+//
+// Each piece of work gets a row, and time runs from left to right. A piece of
+// work is drawn from when it started to when it finished (or failed), using a
+// letter for the thread it started on; the last cell uses the letter of the
+// thread it finished on. For example, this is what `async_concurrent` looks like:
+//
+//                 0ms                                                    500ms
+//         work 1 |AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA|
+//         work 2 |AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA|
+//         work 3 |AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA|
+//         work 4 |AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA|
+//                 A = ThreadId(1)
+//
+// and `sequential`:
+//
+//                 0ms                                                   2002ms
+//         work 1 |AAAAAAAAAAAAAAA                                             |
+//         work 2 |              AAAAAAAAAAAAAAAA                              |
+//         work 3 |                             AAAAAAAAAAAAAAAA               |
+//         work 4 |                                            AAAAAAAAAAAAAAAA|
+//                 A = ThreadId(1)
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::thread::ThreadId;
+use std::time::Instant;
+
+use crate::timer::millis;
+use crate::trace::{Event, Phase};
+
+// The number of columns used for time.
+const WIDTH: usize = 60;
+
+pub fn render(events: &[Event]) -> String {
+    let mut out = String::new();
+    let start = match events.iter().map(|e| e.at).min() {
+        Some(start) => start,
+        None => return out,
+    };
+    let end = events.iter().map(|e| e.at).max().unwrap();
+    let span = millis(end - start);
+
+    // The column for an instant.
+    let column = |at: Instant| {
+        if span == 0 {
+            0
+        } else {
+            (millis(at - start) * (WIDTH as u64 - 1) / span) as usize
+        }
+    };
+
+    // Name threads by letter, in the order they first appear.
+    let mut threads: Vec<ThreadId> = Vec::new();
+    for event in events {
+        if !threads.contains(&event.thread) {
+            threads.push(event.thread);
+        }
+    }
+    let letter = |thread: ThreadId| {
+        let index = threads.iter().position(|t| *t == thread).unwrap();
+        LETTERS.chars().nth(index).unwrap_or('#')
+    };
+
+    // The start and finish (or failure) events for each piece of work, ordered
+    // by id.
+    let mut tasks: BTreeMap<i32, (Option<&Event>, Option<&Event>)> = BTreeMap::new();
+    for event in events {
+        let task = tasks.entry(event.task).or_insert((None, None));
+        match event.phase {
+            Phase::Start => task.0 = Some(event),
+            Phase::Done | Phase::Failed => task.1 = Some(event),
+        }
+    }
+
+    let end_label = format!("{}ms", span);
+    writeln!(out, "{:12}0ms{:>width$}", "", end_label, width = WIDTH - 3).unwrap();
+
+    for (task, (started, done)) in tasks {
+        let mut row = vec![' '; WIDTH];
+        let from = started.map(|e| column(e.at)).unwrap_or(0);
+        let to = done.map(|e| column(e.at)).unwrap_or(WIDTH - 1);
+        let fill = started.or(done).map(|e| letter(e.thread)).unwrap();
+        for cell in &mut row[from..=to] {
+            *cell = fill;
+        }
+        if let Some(done) = done {
+            row[to] = letter(done.thread);
+        }
+
+        let row: String = row.into_iter().collect();
+        let note = match done {
+            None => " (never finished)",
+            Some(e) if e.phase == Phase::Failed => " (failed)",
+            Some(_) => "",
+        };
+        let label = format!("work {}", task);
+        writeln!(out, "{:>10} |{}|{}", label, row, note).unwrap();
+    }
+
+    let legend: Vec<String> = threads
+        .iter()
+        .map(|thread| format!("{} = {:?}", letter(*thread), thread))
+        .collect();
+    writeln!(out, "{:12}{}", "", legend.join(", ")).unwrap();
+    out
+}
+
+const LETTERS: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
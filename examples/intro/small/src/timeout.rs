@@ -0,0 +1,64 @@
+// Putting a time limit on a future.
+//
+// `with_timeout` wraps any future. Each time the wrapper is polled, it polls
+// the inner future and then checks its timeout. If the inner future finishes
+// first, we get its result. If the timeout elapses first, we get an error and
+// the inner future is dropped, i.e., cancelled. It will never be polled again,
+// so any code after the `await` it was waiting at never runs.
+
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{LocalWaker, Poll};
+use std::time::Duration;
+
+use crate::timer::Registration;
+
+// Run `future`, but give up if it hasn't finished within `duration` of calling
+// `with_timeout`.
+pub fn with_timeout<F: Future>(future: F, duration: Duration) -> Timeout<F> {
+    Timeout {
+        future,
+        timeout: Registration::new(duration),
+    }
+}
+
+pub struct Timeout<F> {
+    future: F,
+    timeout: Registration,
+}
+
+// The error when a future runs out of time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "timed out")
+    }
+}
+
+impl Error for Elapsed {}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<Self::Output> {
+        // The inner future might not be safe to move (futures from `async fn`s
+        // usually aren't), so unlike `Delay` we have to be careful with the pin.
+        // This is safe because we never move `future` out of `self`, and we
+        // only ever access it through a pinned reference.
+        let this = unsafe { Pin::get_unchecked_mut(self) };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        // Give the inner future a chance to finish, even if we're late.
+        if let Poll::Ready(output) = future.poll(lw) {
+            return Poll::Ready(Ok(output));
+        }
+
+        // Both the inner future and the timer have our waker, whichever is
+        // ready first will wake us.
+        this.timeout.poll_elapsed(lw).map(|()| Err(Elapsed))
+    }
+}
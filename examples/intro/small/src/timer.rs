@@ -0,0 +1,336 @@
+// A timer which is shared by all the work in the program.
+//
+// Rather than starting a new thread for every timeout, we start a single
+// thread which keeps track of all the deadlines we're waiting for. It sleeps
+// until the earliest deadline, wakes every task whose timeout has expired, then
+// goes back to sleep. When a new timeout is added, we wake the thread up so it
+// can check whether it should wake up sooner.
+//
+// No matter how many tasks are waiting, there is only ever one extra thread.
+// The deadlines are kept in a timing wheel (see `wheel.rs`), so adding and
+// removing a timeout takes the same time however many there are.
+//
+// The timer gets the time from a `Clock` (see `clock.rs`). Normally we use the
+// real time and the timer thread, but for checking the models we can run them
+// with a timer using a virtual clock, which has no thread and only moves when
+// we move it (see `run_with_virtual_clock`).
+
+use lazy_static::lazy_static;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{local_waker_from_nonlocal, LocalWaker, Poll, Wake, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock, VirtualClock};
+use crate::executor;
+use crate::wheel::{Key, Wheel};
+
+lazy_static! {
+    // The timer used unless another is chosen with `with_timer`. The timer
+    // thread is started the first time it is used.
+    static ref DEFAULT: Timer = Timer::system();
+}
+
+thread_local! {
+    // The timer chosen with `with_timer` for this thread, if any.
+    static CURRENT: RefCell<Option<Timer>> = RefCell::new(None);
+}
+
+// Use `timer` for any timeouts started by `f` on this thread.
+pub fn with_timer<R>(timer: &Timer, f: impl FnOnce() -> R) -> R {
+    // Put the previous timer back when we're done, even if `f` panics.
+    struct Reset(Option<Timer>);
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+        }
+    }
+
+    let previous = CURRENT.with(|current| current.borrow_mut().replace(timer.clone()));
+    let _reset = Reset(previous);
+    f()
+}
+
+// The timer for timeouts started on this thread.
+pub fn current() -> Timer {
+    CURRENT
+        .with(|current| current.borrow().clone())
+        .unwrap_or_else(|| DEFAULT.clone())
+}
+
+// The current time, according to the current timer.
+pub fn now() -> Instant {
+    current().shared.clock.now()
+}
+
+// Block this thread for `duration`, according to the current timer.
+pub fn sleep(duration: Duration) {
+    // Let the executor know, in case we're blocking one of its tasks.
+    executor::blocking();
+    current().shared.clock.sleep(duration);
+}
+
+// `duration` in whole milliseconds, which is how we measure and print time.
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+// The number of timeouts the current timer is waiting for.
+pub fn pending() -> usize {
+    current().shared.wheel.lock().unwrap().len()
+}
+
+// Run `future` to completion on this thread using a virtual clock. Whenever
+// the future can't make progress, we move the clock forward by `tick`. Returns
+// the future's output and the number of ticks it took.
+pub fn run_with_virtual_clock<F: Future>(future: F, tick: Duration) -> (F::Output, u32) {
+    // A waker which just records that it has been woken.
+    struct Flag(AtomicBool);
+    impl Wake for Flag {
+        fn wake(arc_self: &Arc<Self>) {
+            arc_self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let clock = Arc::new(VirtualClock::new());
+    let timer = Timer::new(clock.clone());
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let lw = local_waker_from_nonlocal(flag.clone());
+
+    with_timer(&timer, || {
+        let mut future = Box::pin(future);
+        let mut ticks = 0;
+        loop {
+            flag.0.store(false, Ordering::SeqCst);
+            if let Poll::Ready(output) = future.as_mut().poll(&lw) {
+                return (output, ticks);
+            }
+
+            // Keep moving time on until something wakes the future.
+            while !flag.0.load(Ordering::SeqCst) {
+                assert!(timer.pending() > 0, "future is stuck");
+                clock.advance(tick);
+                timer.turn();
+                ticks += 1;
+            }
+        }
+    })
+}
+
+// A timeout which has been handed to the timer.
+//
+// Dropping a `Registration` removes the timeout from the timer, so a future
+// which is dropped before its timeout elapses leaves nothing behind.
+pub struct Registration {
+    entry: Arc<Entry>,
+    key: Key,
+    // The timer we were registered with, so we can cancel the timeout even if
+    // we're dropped on a different thread.
+    timer: Timer,
+}
+
+impl Registration {
+    // Start a timeout which will elapse after `duration`.
+    pub fn new(duration: Duration) -> Registration {
+        Registration::at(now() + duration)
+    }
+
+    // Start a timeout which will elapse at `deadline`.
+    pub fn at(deadline: Instant) -> Registration {
+        let entry = Arc::new(Entry {
+            elapsed: AtomicBool::new(false),
+            waker: Mutex::new(None),
+        });
+        let timer = current();
+        let key = timer.add(deadline, entry.clone());
+        Registration { entry, key, timer }
+    }
+
+    // Check if the timeout has elapsed, without arranging to be woken up.
+    pub fn is_elapsed(&self) -> bool {
+        self.entry.elapsed.load(Ordering::SeqCst)
+    }
+
+    // Check if the timeout has elapsed. If it hasn't, the task which owns `lw`
+    // will be woken up exactly once when it does.
+    pub fn poll_elapsed(&self, lw: &LocalWaker) -> Poll<()> {
+        // We must check the flag while holding the lock, otherwise the timer
+        // thread could fire between our check and storing the waker, and we'd
+        // never be woken up.
+        let mut waker = self.entry.waker.lock().unwrap();
+        if self.is_elapsed() {
+            return Poll::Ready(());
+        }
+
+        // We might be polled many times before the timeout elapses, we only
+        // need to remember the most recent waker.
+        *waker = Some(lw.clone().into_waker());
+        Poll::Pending
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        // If the timeout has already elapsed, the timer has forgotten about it
+        // and there is nothing to do.
+        self.timer.cancel(self.key);
+    }
+}
+
+// The state of a single timeout, shared between its `Registration` and the
+// timer.
+struct Entry {
+    elapsed: AtomicBool,
+    // The waker of the task waiting for this timeout, if it has been polled.
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Entry {
+    fn fire(&self) {
+        let mut waker = self.waker.lock().unwrap();
+        self.elapsed.store(true, Ordering::SeqCst);
+        if let Some(waker) = waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// A handle to a timer. Cloning the handle gives another handle to the same
+// timer.
+#[derive(Clone)]
+pub struct Timer {
+    shared: Arc<Shared>,
+}
+
+// State shared between the timer thread and everyone adding timeouts.
+struct Shared {
+    clock: Arc<dyn Clock>,
+    // The wheel counts time in milliseconds since `start`.
+    start: Instant,
+    wheel: Mutex<Wheel<Arc<Entry>>>,
+    // Used to wake up the timer thread when a timeout is added.
+    condvar: Condvar,
+}
+
+impl Shared {
+    // The wheel tick which `instant` falls in.
+    fn tick_at(&self, instant: Instant) -> u64 {
+        if instant <= self.start {
+            return 0;
+        }
+        millis(instant - self.start)
+    }
+
+    // The first wheel tick which is no earlier than `instant`. We round up so
+    // that timeouts never fire early.
+    fn tick_after(&self, instant: Instant) -> u64 {
+        let tick = self.tick_at(instant);
+        if self.instant_of(tick) < instant {
+            tick + 1
+        } else {
+            tick
+        }
+    }
+
+    fn instant_of(&self, tick: u64) -> Instant {
+        self.start + Duration::from_millis(tick)
+    }
+}
+
+impl Timer {
+    // A timer with no thread. Nothing will fire unless someone calls `turn`.
+    pub fn new(clock: Arc<dyn Clock>) -> Timer {
+        Timer {
+            shared: Arc::new(Shared {
+                start: clock.now(),
+                clock,
+                wheel: Mutex::new(Wheel::new()),
+                condvar: Condvar::new(),
+            }),
+        }
+    }
+
+    // A timer using the real time, with a thread to fire the timeouts.
+    fn system() -> Timer {
+        let timer = Timer::new(Arc::new(SystemClock));
+
+        let thread_timer = timer.clone();
+        thread::Builder::new()
+            .name("timer".to_owned())
+            .spawn(move || thread_timer.run())
+            .expect("could not start the timer thread");
+
+        timer
+    }
+
+    // Fire every timeout which has expired according to the timer's clock.
+    pub fn turn(&self) {
+        let now = self.shared.clock.now();
+        // We let go of the lock before firing timeouts, since waking a task
+        // might end up dropping a `Registration`, which needs the lock to
+        // cancel its timeout.
+        let expired = self
+            .shared
+            .wheel
+            .lock()
+            .unwrap()
+            .advance(self.shared.tick_at(now));
+        for entry in expired {
+            entry.fire();
+        }
+    }
+
+    // When the next timeout will expire, if there are any.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let wheel = self.shared.wheel.lock().unwrap();
+        wheel.next_expiration().map(|tick| self.shared.instant_of(tick))
+    }
+
+    pub fn pending(&self) -> usize {
+        self.shared.wheel.lock().unwrap().len()
+    }
+
+    fn add(&self, deadline: Instant, entry: Arc<Entry>) -> Key {
+        let tick = self.shared.tick_after(deadline);
+        let key = self.shared.wheel.lock().unwrap().insert(tick, entry);
+
+        // The new deadline might be earlier than the one the timer thread is
+        // currently sleeping until.
+        self.shared.condvar.notify_one();
+        key
+    }
+
+    fn cancel(&self, key: Key) {
+        // We don't need to wake the timer thread. If it was going to wake up
+        // for this timeout, it will find nothing to do and go back to sleep.
+        self.shared.wheel.lock().unwrap().cancel(key);
+    }
+
+    // The body of the timer thread.
+    fn run(&self) {
+        loop {
+            self.turn();
+
+            // Sleep until the next deadline, or until we're woken up because a
+            // new timeout was added. Waiting on the condition variable releases
+            // the lock while we sleep. We check the wheel again while holding
+            // the lock, so we can't miss a timeout added since `turn`.
+            let wheel = self.shared.wheel.lock().unwrap();
+            match wheel.next_expiration() {
+                Some(tick) => {
+                    let deadline = self.shared.instant_of(tick);
+                    let now = Instant::now();
+                    if deadline > now {
+                        drop(self.shared.condvar.wait_timeout(wheel, deadline - now).unwrap());
+                    }
+                }
+                None => drop(self.shared.condvar.wait(wheel).unwrap()),
+            }
+        }
+    }
+}
@@ -0,0 +1,279 @@
+// Recording what the work does.
+//
+// Rather than printing messages directly, the work functions record events:
+// which task, what happened, on which thread, and when. Events go to a
+// `Recorder`. By default that is `Stdout`, which prints them just like the
+// original messages, but we can swap in `Memory` to keep the events so that
+// code can look at them afterwards.
+
+use lazy_static::lazy_static;
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, ThreadId};
+use std::time::Instant;
+
+use crate::timer;
+
+lazy_static! {
+    static ref RECORDER: RwLock<Arc<dyn Recorder>> = RwLock::new(Arc::new(Stdout));
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    // The work has started.
+    Start,
+    // The work has finished.
+    Done,
+    // The work has given up with an error.
+    Failed,
+}
+
+#[derive(Clone, Debug)]
+pub struct Event {
+    // The work's id (the `x` passed to the work functions).
+    pub task: i32,
+    pub phase: Phase,
+    // The thread the work was running on when the event happened.
+    pub thread: ThreadId,
+    // When the event happened, according to the current timer's clock.
+    pub at: Instant,
+}
+
+pub trait Recorder: Send + Sync {
+    fn record(&self, event: &Event);
+}
+
+// Record that `task` has reached `phase`.
+pub fn record(task: i32, phase: Phase) {
+    let event = Event {
+        task,
+        phase,
+        thread: thread::current().id(),
+        at: timer::now(),
+    };
+    RECORDER.read().unwrap().record(&event);
+}
+
+// Send all events to `recorder` while running `f`. The recorder is used by all
+// threads, since the multi-threaded model does its work on other threads.
+pub fn with_recorder<R>(recorder: Arc<dyn Recorder>, f: impl FnOnce() -> R) -> R {
+    // Put the previous recorder back when we're done, even if `f` panics.
+    struct Reset(Option<Arc<dyn Recorder>>);
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            *RECORDER.write().unwrap() = self.0.take().unwrap();
+        }
+    }
+
+    let previous = std::mem::replace(&mut *RECORDER.write().unwrap(), recorder);
+    let _reset = Reset(Some(previous));
+    f()
+}
+
+// There's only one recorder, so tests which record events take turns.
+#[cfg(test)]
+pub fn test_lock() -> std::sync::MutexGuard<'static, ()> {
+    lazy_static! {
+        static ref LOCK: Mutex<()> = Mutex::new(());
+    }
+    // A test which fails while holding the lock poisons it, but the lock
+    // doesn't protect any data, so the other tests can carry on.
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// The events from running `model` as a line of JSON, e.g.,
+//
+//     {"model":"async_seq","events":[{"task":1,"phase":"start","thread":"ThreadId(1)","ms":0},...]}
+//
+// Times are in milliseconds since the first event.
+pub fn to_json(model: &str, events: &[Event]) -> String {
+    let start = events.iter().map(|e| e.at).min();
+    let events: Vec<String> = events
+        .iter()
+        .map(|e| {
+            let since = e.at - start.unwrap();
+            let phase = match e.phase {
+                Phase::Start => "start",
+                Phase::Done => "done",
+                Phase::Failed => "failed",
+            };
+            format!(
+                "{{\"task\":{},\"phase\":\"{}\",\"thread\":\"{:?}\",\"ms\":{}}}",
+                e.task,
+                phase,
+                e.thread,
+                timer::millis(since)
+            )
+        })
+        .collect();
+    // Model names don't contain anything which needs escaping.
+    format!("{{\"model\":\"{}\",\"events\":[{}]}}", model, events.join(","))
+}
+
+// Print events as they happen.
+pub struct Stdout;
+
+impl Recorder for Stdout {
+    fn record(&self, event: &Event) {
+        match event.phase {
+            Phase::Start => println!("starting work {} on thread {:?}", event.task, event.thread),
+            Phase::Done => println!("work done! {} on thread {:?}", event.task, event.thread),
+            Phase::Failed => println!("work failed! {} on thread {:?}", event.task, event.thread),
+        }
+    }
+}
+
+// Ignore events.
+pub struct Discard;
+
+impl Recorder for Discard {
+    fn record(&self, _: &Event) {}
+}
+
+// Keep events in memory.
+pub struct Memory {
+    events: Mutex<Vec<Event>>,
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory {
+            events: Mutex::new(Vec::new()),
+        }
+    }
+
+    // The events recorded so far, in the order they were recorded.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    // Just the task and phase of each event, which is often all we care about.
+    pub fn phases(&self) -> Vec<(i32, Phase)> {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| (e.task, e.phase))
+            .collect()
+    }
+}
+
+impl Recorder for Memory {
+    fn record(&self, event: &Event) {
+        self.events.lock().unwrap().push(event.clone());
+    }
+}
+
+// Send events to two recorders.
+pub struct Tee(pub Arc<dyn Recorder>, pub Arc<dyn Recorder>);
+
+impl Recorder for Tee {
+    fn record(&self, event: &Event) {
+        self.0.record(event);
+        self.1.record(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::clock::{Clock, VirtualClock};
+    use crate::timer::{self, Timer};
+    use super::Phase::{Done, Failed, Start};
+
+    #[test]
+    fn memory_keeps_events() {
+        let _lock = test_lock();
+        let clock = Arc::new(VirtualClock::new());
+        let start = clock.now();
+        let events = Arc::new(Memory::new());
+        with_recorder(events.clone(), || {
+            timer::with_timer(&Timer::new(clock.clone()), || {
+                record(1, Start);
+                clock.advance(Duration::from_millis(500));
+                record(1, Done);
+                record(2, Failed);
+            })
+        });
+
+        let events = events.events();
+        let phases: Vec<_> = events.iter().map(|e| (e.task, e.phase)).collect();
+        assert_eq!(phases, vec![(1, Start), (1, Done), (2, Failed)]);
+        assert!(events.iter().all(|e| e.thread == thread::current().id()));
+        // Timestamps come from the current timer's clock.
+        assert_eq!(events[0].at, start);
+        assert_eq!(events[1].at, start + Duration::from_millis(500));
+        assert_eq!(events[2].at, events[1].at);
+    }
+
+    #[test]
+    fn events_record_their_thread() {
+        let _lock = test_lock();
+        let events = Arc::new(Memory::new());
+        let other = with_recorder(events.clone(), || {
+            record(1, Start);
+            thread::spawn(|| {
+                record(2, Start);
+                thread::current().id()
+            })
+            .join()
+            .unwrap()
+        });
+
+        let events = events.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].thread, thread::current().id());
+        assert_eq!(events[1].thread, other);
+        assert_ne!(other, thread::current().id());
+        assert!(events[0].at <= events[1].at);
+    }
+
+    #[test]
+    fn previous_recorder_is_put_back() {
+        let _lock = test_lock();
+        let outer = Arc::new(Memory::new());
+        let inner = Arc::new(Memory::new());
+        with_recorder(outer.clone(), || {
+            with_recorder(inner.clone(), || record(1, Start));
+            record(2, Start);
+        });
+        assert_eq!(inner.phases(), vec![(1, Start)]);
+        assert_eq!(outer.phases(), vec![(2, Start)]);
+    }
+
+    #[test]
+    fn tee_records_to_both() {
+        let _lock = test_lock();
+        let first = Arc::new(Memory::new());
+        let second = Arc::new(Memory::new());
+        with_recorder(Arc::new(Tee(first.clone(), second.clone())), || {
+            record(1, Start);
+        });
+        assert_eq!(first.phases(), vec![(1, Start)]);
+        assert_eq!(second.phases(), vec![(1, Start)]);
+    }
+
+    #[test]
+    fn json() {
+        let start = Instant::now();
+        let event = |task, phase, ms| Event {
+            task,
+            phase,
+            thread: thread::current().id(),
+            at: start + Duration::from_millis(ms),
+        };
+        let events = vec![event(1, Start, 0), event(1, Done, 500)];
+        let thread = format!("{:?}", thread::current().id());
+        assert_eq!(
+            to_json("async_seq", &events),
+            format!(
+                "{{\"model\":\"async_seq\",\"events\":[\
+                 {{\"task\":1,\"phase\":\"start\",\"thread\":\"{0}\",\"ms\":0}},\
+                 {{\"task\":1,\"phase\":\"done\",\"thread\":\"{0}\",\"ms\":500}}]}}",
+                thread
+            )
+        );
+    }
+}
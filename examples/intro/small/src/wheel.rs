@@ -0,0 +1,303 @@
+// A hierarchical timing wheel.
+//
+// The timer thread needs to find the timeouts which have expired, add new
+// timeouts, and remove timeouts which are no longer wanted. With a sorted
+// collection (like a `BTreeMap` or a binary heap), adding and removing cost
+// O(log n), which starts to matter with hundreds of thousands of timeouts.
+//
+// A timing wheel is like a clock face. Time is divided into ticks (one tick is a
+// millisecond for our timer), and the wheel has a slot for each tick. A timeout
+// is put in the slot for the tick when it expires, and as the clock hand moves
+// round, every timeout in the slot it points at has expired. Adding or removing
+// a timeout is just adding or removing it from a slot, which is O(1).
+//
+// A single wheel can only cover as many ticks as it has slots, so we use
+// several wheels (levels), each with 64 slots. A slot in level 0 covers one
+// tick, a slot in level 1 covers 64 ticks, a slot in level 2 covers 64 * 64
+// ticks, and so on. Timeouts far in the future go into a coarse slot in a high
+// level. When the hand of a level reaches a slot, the timeouts in it are moved
+// down into finer slots in the levels below (we say they 'cascade'), until they
+// reach level 0 and expire.
+//
+// See http://www.cs.columbia.edu/~nahum/w6998/papers/sosp87-timing-wheels.pdf
+// for the original paper, and the Tokio timer (which this is loosely based on).
+
+use std::cmp;
+use std::collections::HashMap;
+use std::mem;
+
+// Each level has 2^SLOT_BITS slots.
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 6;
+// The furthest in the future we can set a timeout; later timeouts are moved
+// earlier to this limit. With one millisecond ticks, this is about 12 days.
+const MAX_DELAY: u64 = 1 << (SLOT_BITS * (LEVELS as u32 - 1));
+
+// Identifies a timeout in the wheel so that it can be cancelled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Key(u64);
+
+pub struct Wheel<T> {
+    // `levels[l][s]` holds the timeouts in slot `s` of level `l`, along with the
+    // tick at which each one expires. We use a hash map for each slot so that
+    // we can remove a timeout without searching for it.
+    levels: Vec<Vec<HashMap<Key, (u64, T)>>>,
+    // The level and slot of every timeout in the wheel.
+    locations: HashMap<Key, (usize, usize)>,
+    // The current tick. Every timeout which expires at or before this tick has
+    // been removed from the wheel.
+    now: u64,
+    next_key: u64,
+}
+
+impl<T> Wheel<T> {
+    pub fn new() -> Wheel<T> {
+        Wheel {
+            levels: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| HashMap::new()).collect())
+                .collect(),
+            locations: HashMap::new(),
+            now: 0,
+            next_key: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.locations.len()
+    }
+
+    // Add a timeout which expires at tick `when`. A timeout which should have
+    // already expired will expire at the next tick.
+    pub fn insert(&mut self, when: u64, value: T) -> Key {
+        let when = cmp::min(cmp::max(when, self.now + 1), self.now + MAX_DELAY);
+        let key = Key(self.next_key);
+        self.next_key += 1;
+
+        let placed = self.place(key, when, value);
+        debug_assert!(placed.is_none());
+        key
+    }
+
+    // Remove a timeout before it expires. Returns `None` if the timeout has
+    // already expired (or been cancelled).
+    pub fn cancel(&mut self, key: Key) -> Option<T> {
+        let (level, slot) = self.locations.remove(&key)?;
+        self.levels[level][slot].remove(&key).map(|(_, value)| value)
+    }
+
+    // The tick at which the wheel next has work to do, i.e., when a timeout
+    // expires or some timeouts cascade to a lower level. Returns `None` if the
+    // wheel is empty.
+    pub fn next_expiration(&self) -> Option<u64> {
+        // Every timeout in a level is processed before any timeout in the levels
+        // above it, so we only need to find the first level with any timeouts.
+        for level in 0..LEVELS {
+            let bits = SLOT_BITS * level as u32;
+            let current = slot_for(self.now, level);
+            // Timeouts are always in a slot after the current one (see `place`).
+            for slot in current + 1..SLOTS {
+                if !self.levels[level][slot].is_empty() {
+                    let rotation = self.now >> (bits + SLOT_BITS) << (bits + SLOT_BITS);
+                    return Some(rotation | (slot as u64) << bits);
+                }
+            }
+        }
+
+        // A timeout in the top level can also be in a slot before the hand, to
+        // expire when the hand has gone round once more (see `place`).
+        let bits = SLOT_BITS * (LEVELS as u32 - 1);
+        for slot in 0..=slot_for(self.now, LEVELS - 1) {
+            if !self.levels[LEVELS - 1][slot].is_empty() {
+                let rotation = ((self.now >> (bits + SLOT_BITS)) + 1) << (bits + SLOT_BITS);
+                return Some(rotation | (slot as u64) << bits);
+            }
+        }
+        None
+    }
+
+    // Move the wheel forward to tick `target`, returning every timeout which
+    // has expired.
+    pub fn advance(&mut self, target: u64) -> Vec<T> {
+        let mut expired = Vec::new();
+        // We skip straight past any ticks where there is nothing to do.
+        while let Some(next) = self.next_expiration() {
+            if next > target {
+                break;
+            }
+            self.now = next;
+            self.process_tick(&mut expired);
+        }
+        self.now = cmp::max(self.now, target);
+        expired
+    }
+
+    // Process the slots which the hands are pointing at for the current tick.
+    fn process_tick(&mut self, expired: &mut Vec<T>) {
+        // When a hand moves to a new slot in a higher level, then the hands in
+        // all the lower levels are back at slot 0. We start at the top so that
+        // timeouts can cascade all the way down in one tick.
+        for level in (1..LEVELS).rev() {
+            let bits = SLOT_BITS * level as u32;
+            if self.now & ((1 << bits) - 1) != 0 {
+                continue;
+            }
+
+            let slot = slot_for(self.now, level);
+            let timeouts = mem::replace(&mut self.levels[level][slot], HashMap::new());
+            for (key, (when, value)) in timeouts {
+                self.locations.remove(&key);
+                if let Some(value) = self.place(key, when, value) {
+                    expired.push(value);
+                }
+            }
+        }
+
+        let slot = slot_for(self.now, 0);
+        for (key, (_, value)) in self.levels[0][slot].drain() {
+            self.locations.remove(&key);
+            expired.push(value);
+        }
+    }
+
+    // Put a timeout in the right slot for the current tick. If the timeout has
+    // already expired, it is returned instead.
+    fn place(&mut self, key: Key, when: u64, value: T) -> Option<T> {
+        if when <= self.now {
+            return Some(value);
+        }
+
+        // The level is decided by the most significant bit where `now` and
+        // `when` differ. If they only differ in the lowest six bits, then the
+        // timeout expires before the level 0 hand goes all the way round and it
+        // goes in level 0. If they differ in the next six bits, it goes in
+        // level 1, and so on. Since `when > now`, the timeout's slot is always
+        // after the slot the hand is pointing at.
+        //
+        // If adding the delay to `now` carries past the top level's bits, they
+        // differ in a bit above every level. The delay is at most `MAX_DELAY`,
+        // one top level slot, so the timeout goes in the top level, in the slot
+        // after the hand once it has gone round (i.e., slot 0).
+        let significant = 63 - ((self.now ^ when) | (SLOTS as u64 - 1)).leading_zeros();
+        let level = cmp::min((significant / SLOT_BITS) as usize, LEVELS - 1);
+        let slot = slot_for(when, level);
+
+        self.levels[level][slot].insert(key, (when, value));
+        self.locations.insert(key, (level, slot));
+        None
+    }
+}
+
+// The slot in `level` which covers `tick`.
+fn slot_for(tick: u64, level: usize) -> usize {
+    ((tick >> (SLOT_BITS * level as u32)) & (SLOTS as u64 - 1)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Delays either side of the boundaries between levels.
+    const DELAYS: &[u64] = &[1, 2, 63, 64, 65, 4095, 4096, 4097, MAX_DELAY - 1, MAX_DELAY];
+
+    // Check that `value` expires at exactly tick `when`, and not before.
+    fn assert_expires(wheel: &mut Wheel<u64>, when: u64, value: u64) {
+        assert_eq!(wheel.advance(when - 1), Vec::<u64>::new());
+        assert_eq!(wheel.advance(when), vec![value]);
+    }
+
+    #[test]
+    fn expires_at_level_boundaries() {
+        for &start in &[0, 1, 63, 64, 1000, 4095, 4096] {
+            for &delay in DELAYS {
+                let mut wheel = Wheel::new();
+                wheel.advance(start);
+                wheel.insert(start + delay, delay);
+                assert_eq!(wheel.len(), 1);
+                assert_expires(&mut wheel, start + delay, delay);
+                assert_eq!(wheel.len(), 0);
+                assert_eq!(wheel.next_expiration(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn expires_in_order() {
+        let mut wheel = Wheel::new();
+        for &delay in DELAYS.iter().rev() {
+            wheel.insert(delay, delay);
+        }
+        for &delay in DELAYS {
+            assert_expires(&mut wheel, delay, delay);
+        }
+        assert_eq!(wheel.len(), 0);
+    }
+
+    #[test]
+    fn advance_past_many_timeouts() {
+        let mut wheel = Wheel::new();
+        for &delay in DELAYS {
+            wheel.insert(delay, delay);
+        }
+        assert_eq!(wheel.advance(MAX_DELAY), DELAYS.to_vec());
+    }
+
+    #[test]
+    fn clamps_timeouts() {
+        let mut wheel = Wheel::new();
+        wheel.advance(100);
+        wheel.insert(50, 1);
+        wheel.insert(100 + 3 * MAX_DELAY, 2);
+        assert_expires(&mut wheel, 101, 1);
+        assert_expires(&mut wheel, 100 + MAX_DELAY, 2);
+    }
+
+    #[test]
+    fn cancel() {
+        let mut wheel = Wheel::new();
+        let keys: Vec<_> = DELAYS.iter().map(|&delay| wheel.insert(delay, delay)).collect();
+        // Cancel every other timeout.
+        for (i, &key) in keys.iter().enumerate().step_by(2) {
+            assert_eq!(wheel.cancel(key), Some(DELAYS[i]));
+            assert_eq!(wheel.cancel(key), None);
+        }
+        assert_eq!(wheel.len(), DELAYS.len() / 2);
+
+        let expected: Vec<_> = DELAYS.iter().cloned().skip(1).step_by(2).collect();
+        assert_eq!(wheel.advance(MAX_DELAY), expected);
+        // Expired timeouts can't be cancelled.
+        assert_eq!(wheel.cancel(keys[1]), None);
+    }
+
+    #[test]
+    fn cancel_after_cascading() {
+        let mut wheel = Wheel::new();
+        let key = wheel.insert(5000, 1);
+        // At tick 4096, the timeout moves down from level 2 to level 1.
+        assert_eq!(wheel.advance(4096), Vec::<u64>::new());
+        assert_eq!(wheel.cancel(key), Some(1));
+        assert_eq!(wheel.advance(MAX_DELAY), Vec::<u64>::new());
+    }
+
+    // When a deadline carries past the top level's bits, the timeout must still
+    // expire on time.
+    #[test]
+    fn carries_past_the_top_level() {
+        let top = 1 << (SLOT_BITS * LEVELS as u32);
+        for &start in &[top - 10, top - 1, 3 * top - 1] {
+            for &delay in DELAYS {
+                let mut wheel = Wheel::new();
+                wheel.advance(start);
+                wheel.insert(start + delay, delay);
+                assert_expires(&mut wheel, start + delay, delay);
+            }
+        }
+
+        let mut wheel = Wheel::new();
+        wheel.advance(top - 10);
+        wheel.insert(top - 5, 1);
+        wheel.insert(top + 20, 2);
+        assert_expires(&mut wheel, top - 5, 1);
+        assert_expires(&mut wheel, top + 20, 2);
+    }
+}
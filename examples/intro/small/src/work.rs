@@ -2,58 +2,286 @@
 use futures::future::poll_fn;
 
 // Some threading primitives.
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::task::Poll;
-use std::thread::{self, sleep};
-use std::time::Duration;
-
-// The work here is just waiting for a timeout. We'll print a message before and
-// after.
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{LocalWaker, Poll};
+use std::time::{Duration, Instant};
+
+use crate::rng::Rng;
+use crate::timer::{self, Registration};
+use crate::trace::{self, Phase};
+
+// Counts how many times the futures in this file have been polled, so we can see
+// how much work the executor is doing.
+static POLLS: AtomicUsize = AtomicUsize::new(0);
+
+// Returns the number of polls since the last call, and resets the count.
+pub fn take_poll_count() -> usize {
+    POLLS.swap(0, Ordering::SeqCst)
+}
+
+// The id of the piece of work which should panic as soon as it starts, or zero
+// if none should. Used to show how each model copes when one piece of work goes
+// wrong (see `panics` in `main.rs`).
+static PANICKING: AtomicUsize = AtomicUsize::new(0);
+
+// Make work `x` panic, or stop any work panicking with `None`.
+pub fn set_panicking(x: Option<i32>) {
+    PANICKING.store(x.map_or(0, |x| x as usize), Ordering::SeqCst);
+}
+
+fn check_panic(x: i32) {
+    if x > 0 && PANICKING.load(Ordering::SeqCst) == x as usize {
+        panic!("work {} panicked", x);
+    }
+}
+
+// How long each piece of work takes, unless we say otherwise.
+pub const WORK_DURATION: Duration = Duration::from_millis(500);
+
+// Makes durations for work which vary randomly between half and one and a half
+// times `base` (usually `WORK_DURATION`). When all the work takes the same time,
+// which task finishes first is down to chance, so you might need to run an
+// example many times to see a reordering. With jitter, the order is different
+// for different seeds, and always the same for the same seed.
+pub struct Jitter {
+    rng: Rng,
+    base: Duration,
+}
+
+impl Jitter {
+    pub fn new(seed: u64, base: Duration) -> Jitter {
+        Jitter {
+            rng: Rng::new(seed),
+            base,
+        }
+    }
+
+    pub fn next_duration(&mut self) -> Duration {
+        let millis = timer::millis(self.base);
+        Duration::from_millis(millis / 2 + self.rng.below(millis + 1))
+    }
+}
+
+// Durations for `n` pieces of work. With a seed they are jittered, without they
+// all take `base`.
+pub fn durations(seed: Option<u64>, n: usize, base: Duration) -> Vec<Duration> {
+    match seed {
+        Some(seed) => {
+            let mut jitter = Jitter::new(seed, base);
+            (0..n).map(|_| jitter.next_duration()).collect()
+        }
+        None => vec![base; n],
+    }
+}
+
+// The work here is just waiting for a timeout of `duration`. We'll record an
+// event before and after (see `trace.rs`), which by default prints a message.
 //
-// In order to wait for a timeout, we have to start another thread and wait for
-// a timer there. Since `sleep` is a synchronous function, the thread is blocked
-// until the timeout elapses. If we did this on the main thread, we would block
-// all tasks from making progress.
+// In order to wait for a timeout, we hand it to the timer thread (see
+// `timer.rs`). Since `sleep` is a synchronous function, a thread which sleeps is
+// blocked until the timeout elapses. If we did this on the main thread, we would
+// block all tasks from making progress. Instead, a single timer thread waits for
+// the timeouts of every task at once, so we can have thousands of tasks waiting
+// without thousands of threads.
 //
 // Although we're waiting on another thread to timeout, we're not using threads
-// for scheduling the work. If we wanted we could block on async IO instead or
-// use a single thread for handling all timeouts. (Writing timers and timeouts
-// is surprisingly complicated - https://tokio.rs/blog/2018-03-timers/).
-pub async fn do_work_async(x: i32) {
+// for scheduling the work. If we wanted we could block on async IO instead.
+// (Writing timers and timeouts is surprisingly complicated -
+// https://tokio.rs/blog/2018-03-timers/).
+pub async fn do_work_async(x: i32, duration: Duration) {
     // Starting up, notify the user.
-    println!("starting work {} on thread {:?}", x, thread::current().id());
+    trace::record(x, Phase::Start);
+    check_panic(x);
+
+    // Ask the timer thread to wake us up once the timeout has elapsed.
+    let timeout = Registration::new(duration);
 
-    // We'll wait for this flag to be set by the timeout thread.
-    let flag = Arc::new(AtomicBool::new(false));
-    let timeout_flag = flag.clone();
+    // This task will be polled until it has completed. We handle that in the
+    // below statement. This will all be explained in detail later.
+    await!(poll_fn(|lw| {
+        POLLS.fetch_add(1, Ordering::SeqCst);
+        match timeout.poll_elapsed(lw) {
+            Poll::Ready(()) => {
+                // Work' is done, notify the user and let the scheduler know we're done.
+                trace::record(x, Phase::Done);
+                Poll::Ready(())
+            }
+            // The timeout has not expired yet. The timer thread now has our
+            // waker (`lw`) and will use it to tell the scheduler to poll us
+            // again once the timeout expires. Until then, the scheduler can
+            // forget about us.
+            Poll::Pending => Poll::Pending,
+        }
+    }))
+}
 
-    // Spawn the timeout thread.
-    thread::spawn(move || {
-        // This thread sleeps, then sets the flag.
-        sleep(Duration::from_millis(500));
-        timeout_flag.store(true, Ordering::SeqCst);
-    });
+// How *not* to wait. This version never gives the timer thread a waker. Instead,
+// every time it is polled and the timeout has not expired, it wakes itself
+// straight away so the scheduler polls it again. That works, but the scheduler
+// spins at 100% CPU polling over and over until the timeout expires.
+pub async fn do_work_async_busy(x: i32, duration: Duration) {
+    trace::record(x, Phase::Start);
+
+    let timeout = Registration::new(duration);
 
-    // This task will be repeatedly polled until it has completed. We handle that
-    // in the below statement. This will all be explained in detail later.
     await!(poll_fn(|lw| {
-        if flag.load(Ordering::SeqCst) {
-            // Work' is done, notify the user and let the scheduler know we're done.
-            println!("work done! {} on thread {:?}", x, thread::current().id());
+        POLLS.fetch_add(1, Ordering::SeqCst);
+        if timeout.is_elapsed() {
+            trace::record(x, Phase::Done);
             Poll::Ready(())
         } else {
-            // The timeout has not expired yet. Ask the scheduler to try again
-            // later.
+            // Ask the scheduler to try again immediately.
             lw.wake();
             Poll::Pending
         }
     }))
 }
 
-// A pure sequential version - start, wait, finish.
-pub fn do_work(x: i32) {
-    println!("starting work {} on thread {:?}", x, thread::current().id());
-    sleep(Duration::from_millis(500));
-    println!("work done! {} on thread {:?}", x, thread::current().id());
+// The same work as `do_work_async`, but rather than using `poll_fn`, we wait
+// for a `Delay`, a future we implement by hand below.
+pub async fn do_work_async_delay(x: i32, duration: Duration) {
+    trace::record(x, Phase::Start);
+    await!(Delay::new(duration));
+    trace::record(x, Phase::Done);
+}
+
+// A future which completes once `duration` has elapsed.
+//
+// An `async fn` is compiled into a type much like this one: a struct which holds
+// the state of the computation and implements the `Future` trait. The executor
+// calls `poll` to make progress; each call either finishes (`Poll::Ready`) or
+// arranges for the waker to be called when it is worth polling again and returns
+// `Poll::Pending`.
+pub struct Delay {
+    // When the delay should complete.
+    deadline: Instant,
+    state: DelayState,
+}
+
+enum DelayState {
+    // We haven't been polled yet. Like all futures, a `Delay` does nothing until
+    // it is polled, so the timer doesn't know about us.
+    Idle,
+    // The timer thread has our deadline. The `Registration` is our waker slot:
+    // each time we're polled we store the current waker there, and the timer
+    // thread takes it out and wakes it when the deadline passes.
+    Waiting(Registration),
+    // The deadline has passed.
+    Done,
+}
+
+impl Delay {
+    pub fn new(duration: Duration) -> Delay {
+        Delay {
+            deadline: timer::now() + duration,
+            state: DelayState::Idle,
+        }
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<()> {
+        POLLS.fetch_add(1, Ordering::SeqCst);
+
+        // `Delay` doesn't contain any references to itself, so it is safe to
+        // move and we can treat the pinned reference as a plain `&mut`.
+        let this = &mut *self;
+        loop {
+            match this.state {
+                DelayState::Idle => {
+                    this.state = DelayState::Waiting(Registration::at(this.deadline));
+                }
+                DelayState::Waiting(ref timeout) => match timeout.poll_elapsed(lw) {
+                    Poll::Ready(()) => this.state = DelayState::Done,
+                    Poll::Pending => return Poll::Pending,
+                },
+                DelayState::Done => return Poll::Ready(()),
+            }
+        }
+    }
+}
+
+// A pure sequential version - start, wait for `duration`, finish.
+pub fn do_work(x: i32, duration: Duration) {
+    trace::record(x, Phase::Start);
+    check_panic(x);
+    timer::sleep(duration);
+    trace::record(x, Phase::Done);
+}
+
+// The error from a piece of work which failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WorkError {
+    // The work's id.
+    pub x: i32,
+}
+
+impl fmt::Display for WorkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "work {} failed", self.x)
+    }
+}
+
+impl Error for WorkError {}
+
+// Which pieces of fallible work should fail.
+#[derive(Clone, Debug)]
+pub enum Failures {
+    // Just the work with these ids fails.
+    Only(Vec<i32>),
+    // Each piece of work fails with a chance of `percent` in a hundred. The
+    // same seed always fails the same work.
+    Random { seed: u64, percent: u64 },
+}
+
+impl Failures {
+    // Whether work `x` should fail.
+    pub fn fails(&self, x: i32) -> bool {
+        match self {
+            Failures::Only(xs) => xs.contains(&x),
+            // Work `x` gets the `x`th number from the seed's sequence, so each
+            // piece of work gets its own roll, whichever order we ask in.
+            Failures::Random { seed, percent } => {
+                let mut rng = Rng::new(*seed);
+                for _ in 1..x {
+                    rng.next_u64();
+                }
+                rng.below(100) < *percent
+            }
+        }
+    }
+}
+
+// Like `do_work_async`, but the work can fail. Work which fails gives up half
+// way through. Work which succeeds returns its id, so that we have a result to
+// pass on.
+pub async fn try_do_work_async(x: i32, duration: Duration, fail: bool) -> Result<i32, WorkError> {
+    trace::record(x, Phase::Start);
+    if fail {
+        await!(Delay::new(duration / 2));
+        trace::record(x, Phase::Failed);
+        return Err(WorkError { x });
+    }
+    await!(Delay::new(duration));
+    trace::record(x, Phase::Done);
+    Ok(x)
+}
+
+// The synchronous version of `try_do_work_async`.
+pub fn try_do_work(x: i32, duration: Duration, fail: bool) -> Result<i32, WorkError> {
+    trace::record(x, Phase::Start);
+    if fail {
+        timer::sleep(duration / 2);
+        trace::record(x, Phase::Failed);
+        return Err(WorkError { x });
+    }
+    timer::sleep(duration);
+    trace::record(x, Phase::Done);
+    Ok(x)
 }